@@ -1,9 +1,33 @@
-use jsonschema::JSONSchema;
-use jsonschema::error::{TypeKind, ValidationErrorKind};
+use jsonschema::error::{TypeKind, ValidationError, ValidationErrorKind};
+use jsonschema::paths::{JSONPointer, PathChunk};
 use jsonschema::primitive_type::PrimitiveType;
+use jsonschema::{Draft, JSONSchema};
+use regex::Regex;
 use serde_json::Map;
 use serde_json::Value;
-use std::{collections::HashMap, env};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    path::{Path, PathBuf},
+};
+
+pub mod v2;
+
+pub use v2::{
+    ParseOptions, PropertyInfo, PropertyValue, get_properties, parse_value,
+    parse_value_with_options,
+};
+
+/// The prefix-only API, which maps env vars onto paths without looking at the
+/// schema. These are the same functions as at the crate root, grouped here to
+/// contrast them with the schema-aware [`crate::v2`] API.
+pub mod v1 {
+    pub use crate::{
+        EnvOptions, EnvProperty, process_env_vars, process_env_vars_from,
+        process_env_vars_from_with_options, process_env_vars_iter, process_env_vars_iter_from,
+        process_env_vars_with_options,
+    };
+}
 
 #[derive(Debug, Clone)]
 pub struct EnvProperty {
@@ -12,294 +36,2786 @@ pub struct EnvProperty {
     pub path: String,
 }
 
-/// Fix and validate the generated JSON against the schema. This function
-/// takes the input JSON and the schema as a JSON object, and returns a
-/// Result containing the validated JSON. If the JSON is invalid, a String
-/// containing the error messages is returned. If the JSON is valid, the
-/// same JSON is returned.
-///
-/// If the JSON is invalid, the function will try to fix the errors by
-/// converting the values to the correct type. This is done by parsing the
-/// error messages and modifying the JSON accordingly. If the errors cannot
-/// be fixed, the function will return an error message.
-///
-/// The function takes an additional parameter `retried` which indicates
-/// whether the function has been called before. If `retried` is false, the
-/// function will try to fix the errors and call itself recursively. If
-/// `retried` is true, the function will return an error message without
-/// trying to fix the errors.
-pub fn fix_and_validate_json(
-    schema: &Value,
-    config: Map<String, Value>,
-    retried: bool,
-) -> Result<Map<String, Value>, String> {
-    // Validate the generated JSON against the schema
-    let compiled_schema =
-        JSONSchema::compile(schema).map_err(|e| format!("Failed to compile schema: {}", e))?;
+/// Why `fix_and_validate_json` could not produce a valid config.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FixError {
+    /// The schema itself is not a valid JSON schema.
+    SchemaCompile(String),
+    /// The value at `path` is of a JSON `kind` that cannot be coerced, as only
+    /// strings are.
+    UnsupportedType { path: String, kind: String },
+    /// The string at `path` could not be coerced to `target`; `reason` is the
+    /// message of the coercion that failed.
+    CoercionFailed {
+        path: String,
+        value: String,
+        target: String,
+        reason: String,
+    },
+    /// The config still fails validation after coercion, with one message per
+    /// validation error.
+    ValidationFailed(Vec<String>),
+    /// The env vars could not be turned into a config before fixing it, e.g.
+    /// because two of them need conflicting kinds of value at the same path.
+    InvalidEnv(String),
+}
+
+impl std::fmt::Display for FixError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FixError::SchemaCompile(error) => write!(f, "Failed to compile schema: {}", error),
+            FixError::UnsupportedType { path, kind } => {
+                write!(f, "Existing value at '{}' is not a string: {}", path, kind)
+            }
+            FixError::CoercionFailed {
+                path,
+                value,
+                target,
+                reason,
+            } => write!(
+                f,
+                "Cannot coerce '{}' at '{}' to {}: {}",
+                value, path, target, reason
+            ),
+            FixError::ValidationFailed(messages) => write!(f, "{}", messages.join(", ")),
+            FixError::InvalidEnv(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for FixError {}
+
+/// Fix and validate the generated JSON against the schema. This function
+/// takes the input JSON and the schema as a JSON object, and returns a
+/// Result containing the validated JSON. If the JSON is invalid, a `FixError`
+/// describing what went wrong is returned. If the JSON is valid, the
+/// same JSON is returned.
+///
+/// If the JSON is invalid, the function will try to fix the errors by
+/// converting the values to the correct type. This is done by parsing the
+/// error messages and modifying the JSON accordingly. If the errors cannot
+/// be fixed, the function will return an error message.
+///
+/// Fixing runs in rounds, since a fix can reveal errors inside the value it
+/// produced (e.g. the strings of a JSON array literal that should be
+/// integers). Rounds continue until the config validates, a round changes
+/// nothing, or `FixOptions::max_attempts` rounds have run, after which the
+/// remaining errors are returned. To validate without fixing anything, set
+/// `FixOptions::coerce` to false.
+pub fn fix_and_validate_json(
+    schema: &Value,
+    config: Map<String, Value>,
+) -> Result<Map<String, Value>, FixError> {
+    fix_and_validate_json_with_options(schema, config, &FixOptions::default())
+}
+
+/// How strings are split into arrays when the schema doesn't set an
+/// `x-delimiter`. Newlines delimit items in every mode but `None` and `Char`,
+/// so newline-separated lists (e.g. from secret managers) split as expected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArraySplit {
+    /// Split on whitespace, including newlines.
+    Space,
+    /// Split on commas and newlines.
+    Comma,
+    /// Split on both whitespace and commas.
+    #[default]
+    Both,
+    /// Never split; the whole string becomes a single item.
+    None,
+    Char(char),
+}
+
+impl std::str::FromStr for ArraySplit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "space" => Ok(ArraySplit::Space),
+            "comma" => Ok(ArraySplit::Comma),
+            "both" => Ok(ArraySplit::Both),
+            "none" => Ok(ArraySplit::None),
+            _ => {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(ArraySplit::Char(c)),
+                    _ => Err(format!(
+                        "Invalid array split '{}', expected space, comma, both, none or a single character",
+                        s
+                    )),
+                }
+            }
+        }
+    }
+}
+
+/// Characters that may group the digits of an integer by default, as in
+/// `1_000_000` or `1,000`.
+pub const DEFAULT_GROUPING_SEPARATORS: &[char] = &['_', ','];
+
+/// Options controlling how `fix_and_validate_json_with_options` coerces values.
+#[derive(Debug, Clone)]
+pub struct FixOptions {
+    /// How strings are split into arrays, unless the schema sets `x-delimiter`.
+    pub array_split: ArraySplit,
+    /// Characters allowed to group the digits of integers. `_` may appear
+    /// anywhere between digits, any other separator only between groups of
+    /// three (`1,000,000`).
+    pub grouping_separators: Vec<char>,
+    /// Replace numbers outside their `minimum`/`maximum` with the bound they
+    /// cross instead of failing validation.
+    pub clamp: bool,
+    /// Coerce strings to the types the schema asks for. When off, the config
+    /// is validated as is and every mismatch is reported.
+    pub coerce: bool,
+    /// Maximum number of rounds of fixes to run before giving up.
+    pub max_attempts: usize,
+    /// JSON Schema draft to compile the schema with, instead of detecting it
+    /// from `$schema` (falling back to draft 7).
+    pub draft: Option<Draft>,
+}
+
+impl Default for FixOptions {
+    fn default() -> Self {
+        FixOptions {
+            array_split: ArraySplit::default(),
+            grouping_separators: DEFAULT_GROUPING_SEPARATORS.to_vec(),
+            clamp: false,
+            coerce: true,
+            max_attempts: DEFAULT_MAX_FIX_ATTEMPTS,
+            draft: None,
+        }
+    }
+}
+
+/// Compiles `schema`, forcing `draft` if given.
+fn compile_schema(schema: &Value, draft: Option<Draft>) -> Result<JSONSchema, String> {
+    let mut options = JSONSchema::options();
+    if let Some(draft) = draft {
+        options.with_draft(draft);
+    }
+    options.compile(schema).map_err(|e| e.to_string())
+}
+
+/// The default maximum number of rounds of fixes `fix_and_validate_json` runs.
+pub const DEFAULT_MAX_FIX_ATTEMPTS: usize = 5;
+
+/// Same as `fix_and_validate_json`, but with explicit `FixOptions`.
+pub fn fix_and_validate_json_with_options(
+    schema: &Value,
+    config: Map<String, Value>,
+    options: &FixOptions,
+) -> Result<Map<String, Value>, FixError> {
+    // Validate the generated JSON against the schema
+    let compiled_schema = compile_schema(schema, options.draft).map_err(FixError::SchemaCompile)?;
+
+    let mut config = config;
+    if options.coerce {
+        // "null" and "42" are valid strings, so nullable and union-typed
+        // strings never fail validation
+        normalize_strings(schema, &mut config, options);
+    }
+
+    let max_attempts = if options.coerce {
+        options.max_attempts
+    } else {
+        0
+    };
+    let mut cache = CoercionCache::with_options(options);
+    let mut attempt = 0;
+    loop {
+        let instance = Value::Object(config.clone());
+        let errors: Vec<ValidationError> = match compiled_schema.validate(&instance) {
+            Ok(_) => return Ok(config),
+            Err(errors) => errors.collect(),
+        };
+        // Convert validation errors to a string
+        let failed = || {
+            FixError::ValidationFailed(errors.iter().map(|e| describe_error(schema, e)).collect())
+        };
+        if attempt == max_attempts {
+            return Err(failed());
+        }
+
+        let mut fixed_config = config.clone();
+        for error in &errors {
+            match fix_error(schema, &mut fixed_config, error, &mut cache, options) {
+                // Values coerced in an earlier round are left for validation
+                Err(FixError::UnsupportedType { .. }) if attempt > 0 => {}
+                result => result?,
+            }
+        }
+        if options.clamp {
+            // Bounds can only be checked once values have been coerced
+            clamp_to_bounds(&compiled_schema, &mut fixed_config);
+        }
+        if fixed_config == config {
+            // Nothing left that fixing can change
+            return Err(failed());
+        }
+        config = fixed_config;
+        attempt += 1;
+    }
+}
+
+/// A string value `fix_and_validate_json` converted to another value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coercion {
+    /// Dotted path of the value, e.g. `database.port`.
+    pub path: String,
+    pub original: String,
+    pub value: Value,
+    /// JSON type of the new value, e.g. `integer` or `array`.
+    pub target_type: String,
+}
+
+/// Same as `fix_and_validate_json_with_options`, but also returns a record of
+/// every string in `config` that ended up as a different value, for auditing.
+pub fn fix_and_validate_json_with_report(
+    schema: &Value,
+    config: Map<String, Value>,
+    options: &FixOptions,
+) -> Result<(Map<String, Value>, Vec<Coercion>), FixError> {
+    let original = flatten_config(&config);
+    let fixed = fix_and_validate_json_with_options(schema, config, options)?;
+    let coercions = original
+        .into_iter()
+        .filter_map(|(path, before)| {
+            let Value::String(before) = before else {
+                return None;
+            };
+            let after = value_at_path(&fixed, &path)?;
+            (after.as_str() != Some(before.as_str())).then(|| Coercion {
+                target_type: match after {
+                    Value::Number(n) if n.is_i64() || n.is_u64() => "integer".to_string(),
+                    other => value_type_name(other).to_string(),
+                },
+                value: after.clone(),
+                original: before,
+                path,
+            })
+        })
+        .collect();
+    Ok((fixed, coercions))
+}
+
+/// Replaces every number that violates its `minimum` or `maximum` with that
+/// bound.
+fn clamp_to_bounds(compiled_schema: &JSONSchema, config: &mut Map<String, Value>) {
+    let instance = Value::Object(config.clone());
+    let Err(errors) = compiled_schema.validate(&instance) else {
+        return;
+    };
+    for error in errors {
+        let limit = match &error.kind {
+            ValidationErrorKind::Minimum { limit } | ValidationErrorKind::Maximum { limit } => {
+                limit
+            }
+            _ => continue,
+        };
+        let path_parts = instance_path_parts(&error.instance_path);
+        if let Some(current) = value_at_parts_mut(config, &path_parts) {
+            log::debug!(
+                "Clamped {} at '{}' to {}",
+                current,
+                instance_path_to_string(&error.instance_path),
+                limit
+            );
+            *current = limit.clone();
+        }
+    }
+}
+
+/// Tries to fix a single validation error in place by coercing the offending
+/// string value to the type the schema expects, or to the `enum` option it
+/// matches ignoring case. Other errors are left for validation to report.
+fn fix_error(
+    schema: &Value,
+    config: &mut Map<String, Value>,
+    error: &ValidationError,
+    cache: &mut CoercionCache,
+    options: &FixOptions,
+) -> Result<(), FixError> {
+    // Collect all path chunks to build the full path
+    let path_parts = instance_path_parts(&error.instance_path);
+    let path = instance_path_to_string(&error.instance_path);
+
+    if let ValidationErrorKind::Enum { options } = &error.kind {
+        if let Some(current) = value_at_parts_mut(config, &path_parts)
+            && let Some(canonical) = current.as_str().and_then(|s| match_enum_option(s, options))
+        {
+            log::debug!("Matched {} at '{}' to {}", current, path, canonical);
+            *current = canonical;
+        }
+        return Ok(());
+    }
+
+    if let ValidationErrorKind::Constant { expected_value } = &error.kind {
+        // A stringified const (e.g. "8080" for 8080) takes the const's type;
+        // anything else is left for validation to report
+        if let Some(current) = value_at_parts_mut(config, &path_parts)
+            && let Some(existing) = current.as_str()
+            && serde_json::from_str::<Value>(existing.trim()).ok().as_ref() == Some(expected_value)
+        {
+            log::debug!(
+                "Matched '{}' at '{}' to the const {}",
+                existing,
+                path,
+                expected_value
+            );
+            *current = expected_value.clone();
+        }
+        return Ok(());
+    }
+
+    if let ValidationErrorKind::Type { kind } = &error.kind {
+        let Some(current) = value_at_parts_mut(config, &path_parts) else {
+            log::warn!("Failed to get value at path {}", path);
+            return Ok(());
+        };
+        let candidates = match kind {
+            TypeKind::Single(primitive_type) => vec![*primitive_type],
+            TypeKind::Multiple(types) => by_specificity(types.into_iter().collect()),
+        };
+        let target = candidates
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let existing = match current {
+            Value::String(existing) => existing.clone(),
+            // A gap left by setting only a later index of the array
+            Value::Null
+                if path_parts
+                    .last()
+                    .is_some_and(|p| p.parse::<usize>().is_ok()) =>
+            {
+                return Err(FixError::CoercionFailed {
+                    reason: format!(
+                        "No value was set for '{}', an array index below one that was set, and {} does not allow null",
+                        path, target
+                    ),
+                    path,
+                    value: "null".to_string(),
+                    target,
+                });
+            }
+            other => {
+                return Err(FixError::UnsupportedType {
+                    path,
+                    kind: value_type_name(other).to_string(),
+                });
+            }
+        };
+        let failed = |target: String, reason: String| FixError::CoercionFailed {
+            path: path.clone(),
+            value: existing.clone(),
+            target,
+            reason,
+        };
+
+        let subschema = schema_at_path(schema, &path_parts);
+        let unit = subschema
+            .and_then(|s| s.get("x-env-unit"))
+            .and_then(Value::as_str)
+            .or_else(|| {
+                let format = subschema?.get("x-format")?.as_str()?;
+                let unit = format_unit(format);
+                if unit.is_none() {
+                    log::warn!(
+                        "Unknown x-format '{}' at '{}', coercing as usual",
+                        format,
+                        path
+                    );
+                }
+                unit
+            });
+        let new_value = match (unit, candidates.as_slice()) {
+            (Some(unit), _) => {
+                let parser = unit_parser(unit).ok_or_else(|| {
+                    failed(
+                        unit.to_string(),
+                        format!("Unknown unit '{}' for '{}'", unit, path),
+                    )
+                })?;
+                parser(existing.trim()).map_err(|reason| failed(unit.to_string(), reason))
+            }
+            (None, [primitive_type]) => coerce_value(
+                schema,
+                subschema,
+                &existing,
+                *primitive_type,
+                cache,
+                options,
+            )
+            .map_err(|reason| failed(target, reason)),
+            (None, _) => candidates
+                .iter()
+                .find_map(|t| coerce_value(schema, subschema, &existing, *t, cache, options).ok())
+                .ok_or_else(|| {
+                    let reason = format!(
+                        "Could not coerce '{}' at '{}' to any of: {}",
+                        existing, path, target
+                    );
+                    failed(target.clone(), reason)
+                }),
+        }?;
+        log::debug!("Coerced '{}' at '{}' to {}", existing, path, new_value);
+        *current = new_value;
+    }
+    Ok(())
+}
+
+/// Finds the one `enum` option a string stands for: a string option equal to it
+/// ignoring case, or a non-string option whose JSON form it is (e.g. `2` for
+/// `"2"`). Returns `None` if no option or more than one option matches.
+fn match_enum_option(existing: &str, options: &Value) -> Option<Value> {
+    let mut matches = options.as_array()?.iter().filter(|option| match option {
+        Value::String(option) => option.eq_ignore_ascii_case(existing),
+        option => serde_json::from_str::<Value>(existing.trim()).ok().as_ref() == Some(*option),
+    });
+    match (matches.next(), matches.next()) {
+        (Some(option), None) => Some(option.clone()),
+        _ => None,
+    }
+}
+
+/// Returns the name of a JSON value's type, as used by JSON schema.
+fn value_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Returns true for strings that stand for JSON null: `null` in any case, or
+/// an empty string.
+fn is_null_like(value: &str) -> bool {
+    let value = value.trim();
+    value.is_empty() || value.eq_ignore_ascii_case("null")
+}
+
+/// Orders the members of a union type from most to least specific, which is the
+/// order coercions are attempted in.
+fn by_specificity(mut types: Vec<PrimitiveType>) -> Vec<PrimitiveType> {
+    types.sort_by_key(|t| match t {
+        PrimitiveType::Null => 0,
+        PrimitiveType::Boolean => 1,
+        PrimitiveType::Integer => 2,
+        PrimitiveType::Number => 3,
+        PrimitiveType::Object => 4,
+        PrimitiveType::Array => 5,
+        PrimitiveType::String => 6,
+    });
+    types
+}
+
+/// Rewrites string values that are valid as strings but that the schema would
+/// rather have as another type, since validation never flags them:
+///
+/// * `null` (in any case) becomes JSON null where the schema allows null, and
+///   so do empty strings if the schema does not also allow a string.
+/// * Where a union type allows a string next to booleans or numbers, the value
+///   becomes the first of those it can be coerced to, e.g. `42` for
+///   `["integer", "string"]`.
+fn normalize_strings(schema: &Value, config: &mut Map<String, Value>, options: &FixOptions) {
+    fn walk(root: &Value, value: &mut Value, path: &mut Vec<String>, options: &FixOptions) {
+        match value {
+            Value::Object(map) => {
+                for (key, child) in map.iter_mut() {
+                    path.push(key.clone());
+                    walk(root, child, path, options);
+                    path.pop();
+                }
+            }
+            Value::Array(items) => {
+                for (index, child) in items.iter_mut().enumerate() {
+                    path.push(index.to_string());
+                    walk(root, child, path, options);
+                    path.pop();
+                }
+            }
+            Value::String(existing) => {
+                let types = schema_at_path(root, path)
+                    .map(declared_types)
+                    .unwrap_or_default();
+                if is_null_like(existing) {
+                    let is_empty = existing.trim().is_empty();
+                    if types.contains(&"null") && !(is_empty && types.contains(&"string")) {
+                        *value = Value::Null;
+                    }
+                } else if types.contains(&"string") {
+                    let candidates = types
+                        .iter()
+                        .filter_map(|t| PrimitiveType::try_from(*t).ok())
+                        .filter(|t| {
+                            matches!(
+                                t,
+                                PrimitiveType::Boolean
+                                    | PrimitiveType::Integer
+                                    | PrimitiveType::Number
+                            )
+                        })
+                        .collect();
+                    if let Some(coerced) = by_specificity(candidates)
+                        .into_iter()
+                        .find_map(|t| coerce_scalar(existing, t, &options.grouping_separators).ok())
+                    {
+                        *value = coerced;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (key, value) in config.iter_mut() {
+        walk(schema, value, &mut vec![key.clone()], options);
+    }
+}
+
+/// Returns the type names listed in a schema's `type` keyword.
+fn declared_types(schema: &Value) -> Vec<&str> {
+    match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Walks objects and arrays along `path` (object keys and array indices) and
+/// returns the value found there.
+fn value_at_parts_mut<'a>(
+    config: &'a mut Map<String, Value>,
+    path: &[String],
+) -> Option<&'a mut Value> {
+    let (first, rest) = path.split_first()?;
+    let mut current = config.get_mut(first)?;
+    for part in rest {
+        current = match current {
+            Value::Object(map) => map.get_mut(part)?,
+            Value::Array(items) => items.get_mut(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+/// Returns the paths that still fail once every possible coercion has been
+/// applied: values that could not be coerced plus remaining validation errors.
+pub fn failing_paths(schema: &Value, config: &Map<String, Value>) -> Vec<String> {
+    failing_paths_with_options(schema, config, &FixOptions::default())
+}
+
+/// Same as `failing_paths`, but coercing with explicit `FixOptions`.
+pub fn failing_paths_with_options(
+    schema: &Value,
+    config: &Map<String, Value>,
+    options: &FixOptions,
+) -> Vec<String> {
+    let Ok(compiled_schema) = compile_schema(schema, options.draft) else {
+        return Vec::new();
+    };
+
+    let mut fixed_config = config.clone();
+    let mut cache = CoercionCache::with_options(options);
+    let mut paths = Vec::new();
+    let instance = Value::Object(config.clone());
+    if let Err(errors) = compiled_schema.validate(&instance) {
+        for error in errors {
+            if fix_error(schema, &mut fixed_config, &error, &mut cache, options).is_err() {
+                paths.push(instance_path_to_string(&error.instance_path));
+            }
+        }
+    }
+
+    let instance = Value::Object(fixed_config);
+    if let Err(errors) = compiled_schema.validate(&instance) {
+        for error in errors {
+            paths.push(instance_path_to_string(&error.instance_path));
+        }
+    }
+
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Returns a copy of `config` that only contains the subtrees leading to the
+/// given dotted paths. Paths are cut at the first array index so the whole
+/// array is kept.
+pub fn prune_to_paths(config: &Map<String, Value>, paths: &[String]) -> Map<String, Value> {
+    let mut pruned = Map::new();
+    for path in paths {
+        let object_path = path
+            .split('.')
+            .take_while(|part| part.parse::<usize>().is_err())
+            .collect::<Vec<&str>>()
+            .join(".");
+        if let Some(value) = value_at_path(config, &object_path) {
+            insert_path(&mut pruned, &object_path, value.clone());
+        }
+    }
+    pruned
+}
+
+/// Parses a string carrying a unit (e.g. `2GiB` or `1h30m`) into a JSON value.
+pub type UnitParser = fn(&str) -> Result<Value, String>;
+
+/// Units available to the `x-env-unit` schema keyword. Adding a unit only
+/// requires a parser and an entry here.
+const UNITS: &[(&str, UnitParser)] = &[
+    ("bytes", parse_bytes),
+    ("seconds", parse_seconds),
+    ("percent", parse_percent),
+];
+
+/// Names the `x-format` schema keyword accepts, with the unit whose parser
+/// handles each.
+const FORMATS: &[(&str, &str)] = &[
+    ("duration-seconds", "seconds"),
+    ("byte-size", "bytes"),
+    ("percent", "percent"),
+];
+
+/// Looks up the unit an `x-format` name is parsed as.
+fn format_unit(format: &str) -> Option<&'static str> {
+    FORMATS
+        .iter()
+        .find(|(name, _)| *name == format)
+        .map(|(_, unit)| *unit)
+}
+
+/// Looks up the parser registered for a unit name.
+pub fn unit_parser(name: &str) -> Option<UnitParser> {
+    UNITS
+        .iter()
+        .find(|(unit, _)| *unit == name)
+        .map(|(_, parser)| *parser)
+}
+
+/// Splits a string such as `512MiB` into its numeric part and its suffix.
+fn split_number_suffix(value: &str) -> (&str, &str) {
+    let index = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    (&value[..index], value[index..].trim())
+}
+
+/// Parses a byte size with an optional decimal (`KB`, `MB`, ...) or binary
+/// (`KiB`, `MiB`, ...) suffix into a number of bytes.
+fn parse_bytes(value: &str) -> Result<Value, String> {
+    let (number, suffix) = split_number_suffix(value);
+    let multiplier: u64 = match suffix.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("Invalid byte size: {}", value)),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid byte size: {}", value))?;
+    let bytes = number * multiplier as f64;
+    // `u64::MAX as f64` rounds up to 2^64, which is already out of range.
+    if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+        return Err(format!("Invalid byte size: {}", value));
+    }
+    Ok(Value::Number((bytes as u64).into()))
+}
+
+/// Parses a duration made of one or more `<number><unit>` parts, where the
+/// unit is one of `d`, `h`, `m` or `s` (e.g. `1h30m`), into whole seconds. A
+/// bare number is taken as seconds.
+fn parse_seconds(value: &str) -> Result<Value, String> {
+    let invalid = || format!("Invalid duration: {}", value);
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(Value::Number(seconds.into()));
+    }
+
+    let mut total: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let unit = rest[digits..].chars().next().ok_or_else(invalid)?;
+        let factor = match unit {
+            'd' => 86_400,
+            'h' => 3_600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        total = amount
+            .checked_mul(factor)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+    Ok(Value::Number(total.into()))
+}
+
+/// Parses a percentage such as `75%` into a fraction (`0.75`). A bare number
+/// is used as is.
+fn parse_percent(value: &str) -> Result<Value, String> {
+    let invalid = || format!("Invalid percentage: {}", value);
+    let (number, scale) = match value.strip_suffix('%') {
+        Some(number) => (number.trim(), 100.0),
+        None => (value, 1.0),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    serde_json::Number::from_f64(number / scale)
+        .map(Value::Number)
+        .ok_or_else(invalid)
+}
+
+/// Collects the property names and array indices of an instance path.
+fn instance_path_parts(instance_path: &JSONPointer) -> Vec<String> {
+    instance_path
+        .iter()
+        .filter_map(|chunk| match chunk {
+            PathChunk::Property(prop) => Some(prop.as_ref().to_string()),
+            PathChunk::Index(idx) => Some(idx.to_string()),
+            PathChunk::Keyword(_) => None,
+        })
+        .collect()
+}
+
+/// Renders the instance path of a validation error as a dotted path of
+/// property names and array indices, e.g. `servers.0.host`, the way every
+/// error message of this crate names paths. The root is an empty string.
+pub fn instance_path_to_string(instance_path: &JSONPointer) -> String {
+    instance_path_parts(instance_path).join(".")
+}
+
+/// Formats a validation error for people. Errors that are commonly caused by
+/// the shape of the env vars get a message naming the path and the bounds,
+/// everything else falls back to the jsonschema message.
+fn describe_error(schema: &Value, error: &ValidationError) -> String {
+    let path_parts = instance_path_parts(&error.instance_path);
+    let path = instance_path_to_string(&error.instance_path);
+    let len = error.instance.as_array().map_or(0, Vec::len);
+    let range = || {
+        let subschema = schema_at_path(schema, &path_parts);
+        let bound = |keyword| subschema.and_then(|s| s.get(keyword));
+        match (bound("minimum"), bound("maximum")) {
+            (Some(min), Some(max)) => format!("{} to {}", min, max),
+            (Some(min), None) => format!("at least {}", min),
+            (None, Some(max)) => format!("at most {}", max),
+            (None, None) => "unbounded".to_string(),
+        }
+    };
+    match &error.kind {
+        ValidationErrorKind::MinItems { limit } => format!(
+            "Array at '{}' must contain at least {} items but has {}",
+            path, limit, len
+        ),
+        ValidationErrorKind::MaxItems { limit } => format!(
+            "Array at '{}' must contain at most {} items but has {}",
+            path, limit, len
+        ),
+        ValidationErrorKind::Minimum { limit } => format!(
+            "Value {} at '{}' is below the minimum of {} (allowed range: {})",
+            error.instance,
+            path,
+            limit,
+            range()
+        ),
+        ValidationErrorKind::Maximum { limit } => format!(
+            "Value {} at '{}' is above the maximum of {} (allowed range: {})",
+            error.instance,
+            path,
+            limit,
+            range()
+        ),
+        ValidationErrorKind::AdditionalProperties { unexpected } => {
+            let subschema = schema_at_path(schema, &path_parts);
+            let known: Vec<&str> = subschema
+                .and_then(|s| s.get("properties"))
+                .and_then(Value::as_object)
+                .map(|properties| properties.keys().map(String::as_str).collect())
+                .unwrap_or_default();
+            unexpected
+                .iter()
+                .map(|key| {
+                    let location = join_path(&path, key);
+                    match closest_name(key, &known) {
+                        Some(suggestion) => format!(
+                            "Property '{}' is not allowed by the schema; did you mean '{}'?",
+                            location,
+                            join_path(&path, suggestion)
+                        ),
+                        None => format!("Property '{}' is not allowed by the schema", location),
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(", ")
+        }
+        ValidationErrorKind::Constant { expected_value } => format!(
+            "'{}' is fixed to {} and cannot be overridden (got {})",
+            path, expected_value, error.instance
+        ),
+        ValidationErrorKind::Type { kind } => {
+            let expected = match kind {
+                TypeKind::Single(primitive_type) => primitive_type.to_string(),
+                TypeKind::Multiple(types) => types
+                    .into_iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" or "),
+            };
+            format!(
+                "Value {} at '{}' is not of type {}",
+                error.instance, path, expected
+            )
+        }
+        _ => error.to_string(),
+    }
+}
+
+/// Finds the name in `candidates` closest to `name`, ignoring case, if it is
+/// within a third of the name's length (and at least one) of edits away.
+fn closest_name<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(&name, &candidate.to_lowercase()), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// The number of single-character insertions, deletions, substitutions and
+/// transpositions of adjacent characters needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j] is the distance between a[..i] and b[..j]
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// Converts a string into a value of the given primitive type. `subschema` is
+/// the schema of the property being coerced (if known) and is used to type the
+/// elements of arrays, and to let `"x-bool-as-int": true` integers accept
+/// boolean-like strings as 1 and 0. Arrays and objects may be given as JSON literals; arrays
+/// that are not fall back to delimiter splitting.
+fn coerce_value(
+    root: &Value,
+    subschema: Option<&Value>,
+    existing: &str,
+    primitive_type: PrimitiveType,
+    cache: &mut CoercionCache,
+    options: &FixOptions,
+) -> Result<Value, String> {
+    match primitive_type {
+        PrimitiveType::Array => match parse_json_literal(existing, '[')? {
+            Some(array @ Value::Array(_)) => Ok(array),
+            _ => coerce_array(root, subschema, existing, cache, options),
+        },
+        PrimitiveType::Object => match parse_json_literal(existing, '{')? {
+            Some(object @ Value::Object(_)) => Ok(object),
+            Some(_) => Err(format!("Invalid JSON object: {}", existing)),
+            None => coerce_object(root, subschema, existing, cache, options),
+        },
+        PrimitiveType::Integer
+            if subschema
+                .and_then(|s| s.get("x-bool-as-int"))
+                .and_then(Value::as_bool)
+                .unwrap_or(false) =>
+        {
+            cache.coerce(existing, primitive_type).or_else(|error| {
+                // Boolean-like strings stand for 1 and 0 in legacy flags
+                match cache.coerce(existing, PrimitiveType::Boolean) {
+                    Ok(Value::Bool(flag)) => Ok(Value::from(i64::from(flag))),
+                    _ => Err(error),
+                }
+            })
+        }
+        _ => cache.coerce(existing, primitive_type),
+    }
+}
+
+/// Parses a value written as a JSON literal, e.g. `[{"host":"a"}]`. Values that
+/// do not start like one yield `None`, while values that do but fail to parse
+/// are reported rather than being split into garbage.
+fn parse_json_literal(existing: &str, open: char) -> Result<Option<Value>, String> {
+    let trimmed = existing.trim();
+    if !trimmed.starts_with(open) {
+        return Ok(None);
+    }
+    serde_json::from_str(trimmed)
+        .map(Some)
+        .map_err(|e| format!("Invalid JSON in '{}': {}", trimmed, e))
+}
+
+/// Splits a list the way arrays are split: by the schema's `x-delimiter`, if
+/// set, or else as configured by `options.array_split`. Items are trimmed and
+/// empty ones dropped, unless the schema sets `"x-keep-empty": true`, which
+/// keeps them as empty strings (e.g. `a,,b` becomes `["a", "", "b"]`). When
+/// splitting on both spaces and commas, only commas delimit empty items.
+fn split_list<'a>(
+    subschema: Option<&Value>,
+    existing: &'a str,
+    options: &FixOptions,
+) -> Vec<&'a str> {
+    let delimiter = subschema
+        .and_then(|s| s.get("x-delimiter"))
+        .and_then(Value::as_str)
+        .filter(|d| !d.is_empty());
+    let keep_empty = subschema
+        .and_then(|s| s.get("x-keep-empty"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    if keep_empty && existing.trim().is_empty() {
+        return Vec::new();
+    }
+    let pieces: Vec<&str> = match (delimiter, options.array_split) {
+        (Some(delimiter), _) => existing.split(delimiter).collect(),
+        (None, ArraySplit::Space) => existing.split(char::is_whitespace).collect(),
+        (None, ArraySplit::Comma) => existing.split([',', '\n']).collect(),
+        // Spaces around commas would otherwise delimit empty items
+        (None, ArraySplit::Both) if keep_empty => existing
+            .split(',')
+            .flat_map(|piece| {
+                let words: Vec<&str> = piece.split_whitespace().collect();
+                if words.is_empty() { vec![""] } else { words }
+            })
+            .collect(),
+        (None, ArraySplit::Both) => existing
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .collect(),
+        (None, ArraySplit::None) => vec![existing],
+        (None, ArraySplit::Char(c)) => existing.split(c).collect(),
+    };
+    pieces
+        .into_iter()
+        .map(str::trim)
+        .filter(|s| keep_empty || !s.is_empty())
+        .collect()
+}
+
+/// Builds an object from a list of `key=value` pairs such as
+/// `env=prod,team=payments`, split like an array. Dotted keys build nested
+/// objects the way `create_nested_json` does, so `opts.ssl=true` sets `ssl`
+/// inside `opts`. Values are coerced to the scalar type the schema declares
+/// for their path, if any, and kept as strings otherwise.
+fn coerce_object(
+    root: &Value,
+    subschema: Option<&Value>,
+    existing: &str,
+    cache: &mut CoercionCache,
+    options: &FixOptions,
+) -> Result<Value, String> {
+    let mut object = Map::new();
+    for pair in split_list(subschema, existing, options) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid key=value pair '{}' in '{}'", pair, existing))?;
+        let key = key.trim();
+        if key.is_empty() || key.split('.').any(str::is_empty) {
+            return Err(format!("Missing key in pair '{}' in '{}'", pair, existing));
+        }
+        create_nested_json(&mut object, key, value.trim())?;
+    }
+    let mut object = Value::Object(object);
+    coerce_leaves(root, subschema, &mut object, cache)?;
+    Ok(object)
+}
+
+/// Coerces the strings below `value` to the scalar types `schema` declares
+/// for them, leaving strings without one as they are.
+fn coerce_leaves(
+    root: &Value,
+    schema: Option<&Value>,
+    value: &mut Value,
+    cache: &mut CoercionCache,
+) -> Result<(), String> {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                let child_schema = schema.and_then(|s| child_schema(root, s, key));
+                coerce_leaves(root, child_schema, child, cache)?;
+            }
+        }
+        Value::Array(items) => {
+            for (index, child) in items.iter_mut().enumerate() {
+                let child_schema = schema.and_then(|s| child_schema(root, s, &index.to_string()));
+                coerce_leaves(root, child_schema, child, cache)?;
+            }
+        }
+        Value::String(existing) => {
+            if let Some(value_type) = schema.and_then(|s| scalar_type(root, s)) {
+                *value = cache.coerce(existing, value_type)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Splits a string into an array by the schema's `x-delimiter`, if set, or
+/// else as configured by `options.array_split` (spaces and commas by default),
+/// trimming each item and coercing it to the scalar type declared by the
+/// schema's `items`, if any. Tuples (`prefixItems`) are coerced position by
+/// position and must have at least as many items as the tuple. When the schema
+/// sets `uniqueItems`, duplicates are dropped keeping the first occurrence.
+fn coerce_array(
+    root: &Value,
+    subschema: Option<&Value>,
+    existing: &str,
+    cache: &mut CoercionCache,
+    options: &FixOptions,
+) -> Result<Value, String> {
+    // Tuples are declared with `prefixItems`, or with an array of `items` in
+    // drafts before 2020-12
+    let tuple = subschema
+        .and_then(|s| s.get("prefixItems").or_else(|| s.get("items")))
+        .and_then(Value::as_array);
+    let additional = subschema.and_then(|s| match tuple {
+        Some(_) if s.get("prefixItems").is_some() => s.get("items"),
+        Some(_) => s.get("additionalItems"),
+        None => s.get("items"),
+    });
+    let item_type = additional.and_then(|items| scalar_type(root, items));
+    let pieces = split_list(subschema, existing, options);
+
+    if let Some(tuple) = tuple {
+        let closed = additional == Some(&Value::Bool(false));
+        if pieces.len() < tuple.len() || (closed && pieces.len() > tuple.len()) {
+            return Err(format!(
+                "Expected {} items for tuple but got {}: {}",
+                tuple.len(),
+                pieces.len(),
+                existing
+            ));
+        }
+    }
+
+    let mut items = pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, s)| {
+            let position_type = match tuple {
+                Some(tuple) if index < tuple.len() => scalar_type(root, &tuple[index]),
+                _ => item_type,
+            };
+            match position_type {
+                Some(position_type) => cache.coerce(s, position_type),
+                None => Ok(Value::String(s.to_string())),
+            }
+        })
+        .collect::<Result<Vec<Value>, String>>()?;
+
+    let unique = subschema
+        .and_then(|s| s.get("uniqueItems"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    if unique {
+        let mut seen = Vec::with_capacity(items.len());
+        items.retain(|item| {
+            if seen.contains(item) {
+                return false;
+            }
+            seen.push(item.clone());
+            true
+        });
+    }
+
+    Ok(Value::Array(items))
+}
+
+/// Returns the scalar type declared by a schema (following a `$ref`), if any.
+fn scalar_type(root: &Value, schema: &Value) -> Option<PrimitiveType> {
+    follow_ref(root, schema)
+        .get("type")
+        .and_then(Value::as_str)
+        .and_then(|t| PrimitiveType::try_from(t).ok())
+        .filter(|t| !matches!(t, PrimitiveType::Array | PrimitiveType::Object))
+}
+
+/// Memoizes `coerce_scalar` by value and target type, so a value repeated
+/// across many env vars is only parsed once. A cache is created for each
+/// `fix_and_validate_json` call and dropped with it, so results never carry
+/// over between runs with different schemas or options.
+#[derive(Debug)]
+pub(crate) struct CoercionCache {
+    // One map per target type, indexed by `type_index`, so lookups borrow the
+    // value instead of building an owned key
+    entries: [HashMap<String, Result<Value, String>>; 7],
+    grouping_separators: Vec<char>,
+}
+
+impl CoercionCache {
+    /// Creates a cache that coerces as configured by `options`.
+    pub(crate) fn with_options(options: &FixOptions) -> Self {
+        CoercionCache {
+            entries: Default::default(),
+            grouping_separators: options.grouping_separators.clone(),
+        }
+    }
+
+    /// Coerces `existing` to `primitive_type`, reusing an earlier result for the
+    /// same value and type.
+    pub(crate) fn coerce(
+        &mut self,
+        existing: &str,
+        primitive_type: PrimitiveType,
+    ) -> Result<Value, String> {
+        let entries = &mut self.entries[type_index(primitive_type)];
+        if let Some(cached) = entries.get(existing) {
+            return cached.clone();
+        }
+        let result = coerce_scalar(existing, primitive_type, &self.grouping_separators);
+        entries.insert(existing.to_string(), result.clone());
+        result
+    }
+}
+
+/// Numbers the primitive types for `CoercionCache`, as `PrimitiveType` is not
+/// `Hash`.
+fn type_index(primitive_type: PrimitiveType) -> usize {
+    match primitive_type {
+        PrimitiveType::Array => 0,
+        PrimitiveType::Boolean => 1,
+        PrimitiveType::Integer => 2,
+        PrimitiveType::Null => 3,
+        PrimitiveType::Number => 4,
+        PrimitiveType::Object => 5,
+        PrimitiveType::String => 6,
+    }
+}
+
+/// Converts a string taken from an environment variable into a value of the
+/// given primitive type, returning an error if the string cannot be represented
+/// as that type. Integers may group their digits with `grouping_separators`.
+/// Surrounding whitespace, such as the trailing newline of a value read from a
+/// file, is ignored for every type but `string`, which is kept as is.
+fn coerce_scalar(
+    existing: &str,
+    primitive_type: PrimitiveType,
+    grouping_separators: &[char],
+) -> Result<Value, String> {
+    let trimmed = existing.trim();
+    match primitive_type {
+        PrimitiveType::Array => Err("Unsupported type: Array".to_string()),
+        PrimitiveType::Boolean => match trimmed.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" | "enabled" => Ok(Value::Bool(true)),
+            "false" | "no" | "off" | "0" | "disabled" => Ok(Value::Bool(false)),
+            _ => Err("Unsupported type: Boolean".to_string()),
+        },
+        PrimitiveType::Integer => {
+            if let Some(value) = parse_integer(trimmed, grouping_separators) {
+                Ok(Value::Number(value.into()))
+            } else {
+                Err("Unsupported type: Integer".to_string())
+            }
+        }
+        PrimitiveType::Null => {
+            if is_null_like(trimmed) {
+                Ok(Value::Null)
+            } else {
+                Err("Unsupported type: Null".to_string())
+            }
+        }
+        PrimitiveType::Number => {
+            if let Ok(value) = trimmed.parse::<serde_json::Number>() {
+                return Ok(Value::Number(value));
+            }
+            // Shorthands JSON doesn't allow, such as `.5` or `5.`
+            match trimmed.parse::<f64>() {
+                Ok(value) if value.is_finite() => serde_json::Number::from_f64(value)
+                    .map(Value::Number)
+                    .ok_or_else(|| "Unsupported type: Number".to_string()),
+                Ok(_) => Err(format!(
+                    "Number '{}' is not finite, which JSON cannot represent",
+                    trimmed
+                )),
+                Err(_) => Err("Unsupported type: Number".to_string()),
+            }
+        }
+        PrimitiveType::Object => Err("Unsupported type: Object".to_string()),
+        PrimitiveType::String => Ok(Value::String(existing.to_string())),
+    }
+}
+
+/// Parses an integer whose digits may be grouped with `separators`.
+/// Underscores may sit between any two digits, while other separators must
+/// split the digits into groups of three, so `1,000` parses but `1,2,3` (more
+/// likely a list) doesn't. Hexadecimal (`0xFF`), octal (`0o755`) and binary
+/// (`0b101`) literals are accepted as well.
+fn parse_integer(value: &str, separators: &[char]) -> Option<i64> {
+    if let Ok(value) = value.parse::<i64>() {
+        return Some(value);
+    }
+
+    let (sign, digits) = match value.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", value.strip_prefix('+').unwrap_or(value)),
+    };
+
+    let radix = match digits.get(..2).map(str::to_ascii_lowercase).as_deref() {
+        Some("0x") => Some(16),
+        Some("0o") => Some(8),
+        Some("0b") => Some(2),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        let digits = &digits[2..];
+        let digits = match separators.contains(&'_') {
+            true => digits.replace('_', ""),
+            false => digits.to_string(),
+        };
+        // from_str_radix would accept a second sign after the prefix
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return None;
+        }
+        return i64::from_str_radix(&format!("{}{}", sign, digits), radix).ok();
+    }
+
+    let digits = if separators.contains(&'_') {
+        if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+            return None;
+        }
+        digits.replace('_', "")
+    } else {
+        digits.to_string()
+    };
+
+    let mut used = separators
+        .iter()
+        .filter(|&&c| c != '_' && digits.contains(c));
+    let digits = match (used.next(), used.next()) {
+        (None, _) => digits,
+        (Some(&sep), None) => {
+            let groups: Vec<&str> = digits.split(sep).collect();
+            let well_formed = (1..=3).contains(&groups[0].len())
+                && groups[1..].iter().all(|group| group.len() == 3);
+            if !well_formed {
+                return None;
+            }
+            groups.concat()
+        }
+        // Mixing separators such as `1,000.000` is ambiguous
+        (Some(_), Some(_)) => return None,
+    };
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    format!("{}{}", sign, digits).parse().ok()
+}
+
+/// Recursively creates a nested JSON object based on the given `path` and sets the value
+/// to the given `value`.
+///
+/// The `path` is split by dots (`.`) and each part is used to create a nested JSON
+/// object. If the part is a number, it is used as an array index, otherwise it is used as
+/// a key in an object.
+///
+/// For example, the `path` `"a.b.0.c"` produces `{"a": {"b": [{"c": value}]}}`.
+/// Arrays may nest directly inside arrays, e.g. `"matrix.1.0"` produces
+/// `{"matrix": [null, [value]]}`. Array indices below the highest one set are
+/// null until a path sets them.
+///
+/// An array may also be given inline and extended by indexed paths, e.g.
+/// `foo` set to `"a,b"` and `foo.2` to `"c"` produce `["a", "b", "c"]`. The
+/// inline list is split on spaces and commas (see
+/// `create_nested_json_for_schema` to split it by the schema), and where both
+/// set the same index, the indexed path wins, whichever of them is set first.
+///
+/// Returns an error naming both sides if `path` conflicts with a path set
+/// earlier, e.g. `a.b` set to a string and then `a.b.c` needing an object, and
+/// if it indexes an array beyond `MAX_ARRAY_INDEX`.
+pub fn create_nested_json(
+    config: &mut Map<String, Value>,
+    path: &str,
+    value: &str,
+) -> Result<(), String> {
+    create_nested_json_with_sep(config, path, value, '.')
+}
+
+/// Same as `create_nested_json`, but splits `path` on `sep` instead of `.`, so
+/// keys may contain literal dots (e.g. `versions/v1.2/enabled` with `/`).
+pub fn create_nested_json_with_sep(
+    config: &mut Map<String, Value>,
+    path: &str,
+    value: &str,
+    sep: char,
+) -> Result<(), String> {
+    let split = |_: &[&str], list: &str| inline_list_items(None, list, &FixOptions::default());
+    set_nested_json(config, path, value, sep, &split)
+}
+
+/// Same as `create_nested_json`, but splits an inline list merged with indexed
+/// paths the way `fix_and_validate_json_with_options` would split it for the
+/// array at its path: by the schema's `x-delimiter` or `options.array_split`,
+/// keeping empty items under `x-keep-empty`, and taking a JSON array literal
+/// as is.
+pub fn create_nested_json_for_schema(
+    config: &mut Map<String, Value>,
+    schema: &Value,
+    path: &str,
+    value: &str,
+    options: &FixOptions,
+) -> Result<(), String> {
+    let split = |array_path: &[&str], list: &str| {
+        let parts: Vec<String> = array_path.iter().map(|part| part.to_string()).collect();
+        inline_list_items(schema_at_path(schema, &parts), list, options)
+    };
+    set_nested_json(config, path, value, '.', &split)
+}
+
+/// Splits the items of an inline list, given the path of the array it sets.
+type InlineListSplit<'a> = dyn Fn(&[&str], &str) -> Vec<Value> + 'a;
+
+/// Sets `value` at `path`, split on `sep`, using `split` for inline lists that
+/// indexed paths extend.
+fn set_nested_json(
+    config: &mut Map<String, Value>,
+    path: &str,
+    value: &str,
+    sep: char,
+    split: &InlineListSplit,
+) -> Result<(), String> {
+    let parts: Vec<&str> = path.split(sep).collect();
+
+    // Arrays are padded up to the index, so a huge one would exhaust memory
+    if let Some(index) = parts
+        .iter()
+        .filter_map(|part| part.parse::<usize>().ok())
+        .find(|index| *index > MAX_ARRAY_INDEX)
+    {
+        return Err(format!(
+            "Array index {} in '{}' exceeds the maximum of {}",
+            index, path, MAX_ARRAY_INDEX
+        ));
+    }
+
+    // Reports that `path` needs the value at its first `depth + 1` parts to be
+    // of type `wanted`, while an earlier path made it `existing`
+    let collision = |depth: usize, existing: &Value, wanted: &str| {
+        format!(
+            "Conflicting paths: '{}' holds a value of type {} but '{}' needs it to be {}",
+            parts[..=depth].join(&sep.to_string()),
+            value_type_name(existing),
+            path,
+            wanted
+        )
+    };
+
+    fn assign(
+        slot: &mut Value,
+        parts: &[&str],
+        depth: usize,
+        value: &str,
+        collision: &dyn Fn(usize, &Value, &str) -> String,
+        split: &InlineListSplit,
+    ) -> Result<(), String> {
+        match slot {
+            Value::Object(_) => Err(collision(depth, slot, "a string")),
+            Value::Array(items) => {
+                // Indexed paths set earlier keep their items over the list's
+                merge_inline_list(items, split(parts, value));
+                Ok(())
+            }
+            _ => {
+                *slot = Value::String(value.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    // Sets the value at `parts[depth..]` below `container`, which holds the
+    // value at `parts[..depth]`. Missing containers are created as arrays when
+    // the part indexing into them is numeric and as objects otherwise, at
+    // every level, so arrays can nest directly inside arrays. Arrays grown to
+    // reach an index are padded with nulls, which later paths may fill.
+    fn set_nested_value(
+        container: &mut Value,
+        parts: &[&str],
+        depth: usize,
+        value: &str,
+        collision: &dyn Fn(usize, &Value, &str) -> String,
+        split: &InlineListSplit,
+    ) -> Result<(), String> {
+        let part = parts[depth];
+        let is_last = depth + 1 == parts.len();
+        let empty_child = || match parts.get(depth + 1) {
+            Some(next) if next.parse::<usize>().is_ok() => Value::Array(Vec::new()),
+            _ => Value::Object(Map::new()),
+        };
+
+        // A list set inline becomes an array that indexed paths extend
+        if let (Value::String(list), Ok(_)) = (&*container, part.parse::<usize>()) {
+            let mut items = Vec::new();
+            merge_inline_list(&mut items, split(&parts[..depth], list));
+            *container = Value::Array(items);
+        }
+
+        let slot = match (container, part.parse::<usize>()) {
+            (Value::Array(arr), Ok(idx)) => {
+                // Indices no env var sets stay null rather than copying the value
+                if arr.len() <= idx {
+                    arr.resize(idx + 1, Value::Null);
+                }
+                &mut arr[idx]
+            }
+            (Value::Object(map), _) if is_last => {
+                map.entry(part.to_string()).or_insert(Value::Null)
+            }
+            (Value::Object(map), _) => map.entry(part.to_string()).or_insert_with(empty_child),
+            (other, Ok(_)) => return Err(collision(depth - 1, other, "an array")),
+            (other, Err(_)) => return Err(collision(depth - 1, other, "an object")),
+        };
+
+        if is_last {
+            assign(slot, parts, depth, value, collision, split)
+        } else {
+            if slot.is_null() {
+                *slot = empty_child();
+            }
+            set_nested_value(slot, parts, depth + 1, value, collision, split)
+        }
+    }
+
+    let mut root = Value::Object(std::mem::take(config));
+    let result = set_nested_value(&mut root, &parts, 0, value, &collision, split);
+    if let Value::Object(map) = root {
+        *config = map;
+    }
+    result
+}
+
+/// Splits an inline `list` into array items like coercion would for an array
+/// described by `subschema`: a JSON array literal gives its items, and any
+/// other value is split by `split_list` into strings.
+fn inline_list_items(subschema: Option<&Value>, list: &str, options: &FixOptions) -> Vec<Value> {
+    if let Ok(Some(Value::Array(items))) = parse_json_literal(list, '[') {
+        return items;
+    }
+    split_list(subschema, list, options)
+        .into_iter()
+        .map(|item| Value::String(item.to_string()))
+        .collect()
+}
+
+/// Fills the null or missing slots of `items` with the items of an inline
+/// list, by position.
+fn merge_inline_list(items: &mut Vec<Value>, list: Vec<Value>) {
+    for (index, item) in list.into_iter().enumerate() {
+        match items.get_mut(index) {
+            Some(slot) if slot.is_null() => *slot = item,
+            Some(_) => {}
+            None => items.push(item),
+        }
+    }
+}
+
+/// The largest array index a path may set.
+pub const MAX_ARRAY_INDEX: usize = 9_999;
+
+/// The default maximum number of path segments an env var may expand to.
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// Options controlling how environment variables are turned into config paths.
+#[derive(Debug, Clone)]
+pub struct EnvOptions {
+    /// Maximum number of segments a transformed path may have. Deeper paths are
+    /// rejected to guard against stack exhaustion while building nested JSON.
+    pub max_depth: usize,
+    /// Explicit overrides from an env segment (uppercase, e.g. `OAUTH2`) to
+    /// the exact schema key it should become (e.g. `oAuth2`), for names the
+    /// automatic transform can't derive.
+    pub key_map: HashMap<String, String>,
+    /// Keep the case of env segments instead of lowercasing them, so that
+    /// `PREFIX_maxConns` maps onto a `maxConns` key.
+    pub preserve_case: bool,
+    /// Let runs of env segments match the schema key whose snake_case form
+    /// they spell, so `PREFIX_MAX_CONNS` maps onto `maxConns`. Only used by
+    /// the schema-aware [`crate::v2`] functions.
+    pub match_snake_case: bool,
+    /// Env var names to skip entirely, matched against the full name
+    /// (including the prefix). Patterns may use `*` for any run of characters
+    /// and `?` for a single one, e.g. `PREFIX_INTERNAL_*`.
+    pub ignore: Vec<String>,
+    /// Delimiter between the prefix and the rest of the name, e.g. `__` for
+    /// `APP__DATABASE_HOST` with the prefix `APP`. Names that don't continue
+    /// with it after the prefix are skipped. Empty by default, in which case
+    /// the prefix includes any delimiter.
+    pub prefix_delimiter: String,
+    /// Separator between path segments in the rest of the name (`_` by
+    /// default). Doubling it stands for a literal separator inside a segment.
+    /// Processing env vars fails if it is empty or contains NUL.
+    pub nesting_separator: String,
+}
+
+impl Default for EnvOptions {
+    fn default() -> Self {
+        EnvOptions {
+            max_depth: DEFAULT_MAX_DEPTH,
+            key_map: HashMap::new(),
+            preserve_case: false,
+            match_snake_case: false,
+            ignore: Vec::new(),
+            prefix_delimiter: String::new(),
+            nesting_separator: "_".to_string(),
+        }
+    }
+}
+
+/// Processes environment variables that start with a given prefix and
+/// returns a `BTreeMap` where each key is the original environment variable
+/// name, and each value is an `EnvProperty` containing:
+/// - `env`: the original environment variable name,
+/// - `value`: the value of the environment variable,
+/// - `path`: a transformed version of the key where double underscores (`__`)
+///   are replaced with underscores, underscores (`_`) are replaced with dots (`.`),
+///   and the whole path is converted to lowercase.
+///
+/// # Arguments
+///
+/// * `prefix` - A string slice that holds the prefix to filter environment variables.
+///
+/// # Returns
+///
+/// * `Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>>` - A result containing
+///   a `BTreeMap` of environment variables matching the prefix transformed into `EnvProperty`
+///   structs, or an error. Being sorted by variable name, iterating it applies env vars in
+///   the same order on every run, so the resulting config is reproducible.
+pub fn process_env_vars(
+    prefix: &str,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>> {
+    process_env_vars_with_options(prefix, &EnvOptions::default())
+}
+
+/// Same as `process_env_vars`, but with explicit `EnvOptions`. Returns an error
+/// if a variable's path is deeper than `options.max_depth`.
+pub fn process_env_vars_with_options(
+    prefix: &str,
+    options: &EnvOptions,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>> {
+    process_env_vars_from_with_options(prefix, env::vars(), options)
+}
+
+/// Same as `process_env_vars`, but reads the variables from `vars` instead of
+/// the process environment.
+pub fn process_env_vars_from<I>(
+    prefix: &str,
+    vars: I,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    process_env_vars_from_with_options(prefix, vars, &EnvOptions::default())
+}
+
+/// Same as `process_env_vars_with_options`, but reads the variables from
+/// `vars` instead of the process environment.
+pub fn process_env_vars_from_with_options<I>(
+    prefix: &str,
+    vars: I,
+    options: &EnvOptions,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    collect_properties(process_env_vars_iter_from(prefix, vars, options), options)
+}
+
+/// Same as `process_env_vars_from_with_options`, but selects the variables
+/// whose names match `pattern` instead of those starting with a prefix. The
+/// named group `rest` (or else the first group) is the part of the name
+/// transformed into the path, and the values of any other named groups are
+/// prepended to it as segments, in the order they appear in the pattern, each
+/// transformed like a single segment of the name. For example,
+/// `^SVC(?P<idx>\d+)_(?P<rest>.+)$` maps `SVC1_PORT` to `1.port`.
+/// `prefix_delimiter` is not used.
+pub fn process_env_vars_matching_from<I>(
+    pattern: &Regex,
+    vars: I,
+    options: &EnvOptions,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let rest_index = pattern
+        .capture_names()
+        .position(|name| name == Some("rest"))
+        .or((pattern.captures_len() > 1).then_some(1))
+        .ok_or_else(|| {
+            format!(
+                "The pattern '{}' needs a group capturing the part of the name to map, e.g. (?P<rest>.+)",
+                pattern
+            )
+        })?;
+
+    let properties = vars.into_iter().filter_map(|(key, raw_value)| {
+        let captures = pattern.captures(&key)?;
+        let rest = captures.get(rest_index)?.as_str();
+        let mut segments: Vec<String> = pattern
+            .capture_names()
+            .enumerate()
+            .filter(|(index, name)| name.is_some() && *index != rest_index)
+            .filter_map(|(index, _)| captures.get(index))
+            .map(|group| env_segment_to_key(group.as_str().to_string(), options))
+            .collect();
+        segments.push(env_key_to_path(rest, options));
+        let path = segments.join(".");
+        new_env_property(key, raw_value, path, options)
+    });
+    collect_properties(properties, options)
+}
+
+/// Collects properties keyed by their env var name, rejecting paths deeper
+/// than `options.max_depth`. The iterator is lazy, so an unusable
+/// `options.nesting_separator` is rejected before any name is split with it.
+fn collect_properties(
+    properties: impl Iterator<Item = EnvProperty>,
+    options: &EnvOptions,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>> {
+    if options.nesting_separator.is_empty() {
+        return Err("The nesting separator must not be empty".into());
+    }
+    // NUL marks the escaped separators while splitting a name
+    if options.nesting_separator.contains('\0') {
+        return Err("The nesting separator must not contain NUL".into());
+    }
+    let mut result = BTreeMap::new();
+    for property in properties {
+        let depth = property.path.split('.').count();
+        if depth > options.max_depth {
+            return Err(format!(
+                "Environment variable {} maps to a path {} levels deep, exceeding the maximum of {}",
+                property.env, depth, options.max_depth
+            )
+            .into());
+        }
+        result.insert(property.env.clone(), property);
+    }
+    Ok(result)
+}
+
+/// Lazily transforms the environment variables that start with `prefix` into
+/// `EnvProperty`s, in the order the environment lists them, without collecting
+/// them first. Unlike `process_env_vars`, paths deeper than `max_depth` are
+/// not rejected, so callers folding properties into a config should check.
+pub fn process_env_vars_iter(prefix: &str) -> impl Iterator<Item = EnvProperty> {
+    let options = EnvOptions::default();
+    env::vars().filter_map(move |(key, raw_value)| env_property(prefix, key, raw_value, &options))
+}
+
+/// Same as `process_env_vars_iter`, but reads the variables from `vars` and
+/// transforms their names according to `options`.
+pub fn process_env_vars_iter_from<I>(
+    prefix: &str,
+    vars: I,
+    options: &EnvOptions,
+) -> impl Iterator<Item = EnvProperty>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    vars.into_iter()
+        .filter_map(move |(key, raw_value)| env_property(prefix, key, raw_value, options))
+}
+
+/// Transforms a single variable into an `EnvProperty`, or `None` if its name
+/// doesn't start with `prefix`.
+fn env_property(
+    prefix: &str,
+    key: String,
+    raw_value: String,
+    options: &EnvOptions,
+) -> Option<EnvProperty> {
+    let stripped_key = key
+        .strip_prefix(prefix)?
+        .strip_prefix(options.prefix_delimiter.as_str())?;
+    let path = env_key_to_path(stripped_key, options);
+    new_env_property(key, raw_value, path, options)
+}
+
+/// Builds the property for the variable `key` mapped to `path`, or `None` if
+/// `options.ignore` skips it.
+fn new_env_property(
+    key: String,
+    raw_value: String,
+    path: String,
+    options: &EnvOptions,
+) -> Option<EnvProperty> {
+    if options
+        .ignore
+        .iter()
+        .any(|pattern| glob_matches(pattern, &key))
+    {
+        log::debug!("Ignoring {}", key);
+        return None;
+    }
+
+    // Remove quotes from the start and end of the value if present
+    let value = unquote(raw_value.trim()).unwrap_or(raw_value);
+
+    Some(EnvProperty {
+        env: key,
+        value,
+        path,
+    })
+}
+
+/// Matches `name` against a glob `pattern`, where `*` matches any run of
+/// characters and `?` any single character. Other characters match exactly.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Strips a balanced pair of surrounding single or double quotes, unescaping
+/// `\\` and escaped quotes inside them. Other backslashes are kept as is, so
+/// Windows paths survive. Returns `None` if the value is not wrapped in
+/// matching quotes or holds an unescaped quote of the same kind inside, as in
+/// `"a"b"c"`, which is left untouched.
+fn unquote(value: &str) -> Option<String> {
+    let quote = value.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+    let inner = value.get(1..)?.strip_suffix(quote)?;
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped @ ('\\' | '"' | '\'')) => unquoted.push(escaped),
+                Some(other) => {
+                    unquoted.push('\\');
+                    unquoted.push(other);
+                }
+                // The closing quote was escaped, so the value isn't quoted
+                None => return None,
+            },
+            c if c == quote => return None,
+            c => unquoted.push(c),
+        }
+    }
+    Some(unquoted)
+}
+
+/// Wraps `value` in double quotes if reading it as an env var would otherwise
+/// strip quotes from it, so that it comes out unchanged. Values from
+/// `parse_dotenv` have already been unquoted and go through this before being
+/// mixed with the real environment.
+pub fn quote_env_value(value: &str) -> String {
+    if unquote(value.trim()).is_none() {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Parses the contents of a dotenv file into `(name, value)` pairs, in file
+/// order. Blank lines and lines starting with `#` are skipped, an `export `
+/// prefix is allowed, and values may be wrapped in single or double quotes,
+/// inside which `\\` and escaped quotes are unescaped. Only a ` #` comment
+/// may follow the closing quote. Unquoted values end at an inline ` #`
+/// comment.
+pub fn parse_dotenv(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid line {} in env file: {}", number + 1, line))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("Missing name on line {} in env file", number + 1));
+        }
+
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                // Find the closing quote, skipping escaped characters
+                let mut end = None;
+                let mut chars = value.char_indices().skip(1);
+                while let Some((index, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        c if c == quote => {
+                            end = Some(index);
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                let end = end.ok_or_else(|| {
+                    format!("Unterminated quote on line {} in env file", number + 1)
+                })?;
+                let rest = value[end + 1..].trim_start();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(format!(
+                        "Unexpected text after the closing quote on line {} in env file: {}",
+                        number + 1,
+                        rest
+                    ));
+                }
+                unquote(&value[..=end]).unwrap_or_default()
+            }
+            _ => value
+                .split(" #")
+                .next()
+                .unwrap_or(value)
+                .trim_end()
+                .to_string(),
+        };
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+/// Fills in every property missing from `config` with its schema `default`,
+/// descending into nested objects (and `$ref`s). Nested objects that are absent
+/// from the config are only created when something inside them has a default.
+pub fn apply_defaults(schema: &Value, config: &mut Map<String, Value>) {
+    fn fill(root: &Value, schema: &Value, map: &mut Map<String, Value>) {
+        let schema = follow_ref(root, schema);
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return;
+        };
+
+        for (key, property) in properties {
+            let property = follow_ref(root, property);
+            if !map.contains_key(key) {
+                if let Some(default) = property.get("default") {
+                    map.insert(key.clone(), default.clone());
+                    continue;
+                }
+                if property.get("properties").is_some() {
+                    let mut child = Map::new();
+                    fill(root, property, &mut child);
+                    if !child.is_empty() {
+                        map.insert(key.clone(), Value::Object(child));
+                    }
+                }
+                continue;
+            }
+            if let Some(Value::Object(child)) = map.get_mut(key) {
+                fill(root, property, child);
+            }
+        }
+    }
+
+    fill(schema, schema, config);
+}
+
+/// Lists the dotted path of every property that a `required` array in the
+/// schema asks for but `config` lacks, descending into nested objects present
+/// in the config (and `$ref`s). A missing object is reported once, not for
+/// each of its own required properties.
+pub fn missing_required(schema: &Value, config: &Map<String, Value>) -> Vec<String> {
+    fn walk(
+        root: &Value,
+        schema: &Value,
+        map: &Map<String, Value>,
+        path: &str,
+        out: &mut Vec<String>,
+    ) {
+        let schema = follow_ref(root, schema);
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str);
+        for key in required {
+            if !map.contains_key(key) {
+                out.push(join_path(path, key));
+            }
+        }
+
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return;
+        };
+        for (key, property) in properties {
+            if let Some(Value::Object(child)) = map.get(key) {
+                walk(root, property, child, &join_path(path, key), out);
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    walk(schema, schema, config, "", &mut out);
+    out
+}
+
+/// Merges `overlay` into `base`: objects present in both are merged
+/// recursively, while any other value in `overlay`, arrays included, replaces
+/// the one in `base`.
+pub fn deep_merge(base: &mut Map<String, Value>, overlay: Map<String, Value>) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(value)) => deep_merge(existing, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Replaces string values carrying an inline type hint with the typed value:
+/// `int:42`, `float:1.5`, `bool:true`, `str:text` and `json:{"a":1}`. Values
+/// without a known hint prefix are left as strings. This is an escape hatch
+/// for schemas too loose to say what type a value should have.
+pub fn apply_type_hints(config: &mut Map<String, Value>) -> Result<(), String> {
+    fn walk(value: &mut Value, path: &str) -> Result<(), String> {
+        match value {
+            Value::Object(map) => {
+                for (key, child) in map.iter_mut() {
+                    walk(child, &join_path(path, key))?;
+                }
+            }
+            Value::Array(items) => {
+                for (index, child) in items.iter_mut().enumerate() {
+                    walk(child, &join_path(path, &index.to_string()))?;
+                }
+            }
+            Value::String(existing) => {
+                if let Some(hinted) = parse_type_hint(existing)
+                    .map_err(|e| format!("Invalid type hint at '{}': {}", path, e))?
+                {
+                    *value = hinted;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    for (key, value) in config.iter_mut() {
+        walk(value, key)?;
+    }
+    Ok(())
+}
+
+/// Parses a value with an inline type hint, returning `None` if it has none.
+fn parse_type_hint(value: &str) -> Result<Option<Value>, String> {
+    let Some((hint, rest)) = value.split_once(':') else {
+        return Ok(None);
+    };
+    let parsed = match hint {
+        "int" => coerce_scalar(rest, PrimitiveType::Integer, DEFAULT_GROUPING_SEPARATORS),
+        "float" => coerce_scalar(rest, PrimitiveType::Number, DEFAULT_GROUPING_SEPARATORS),
+        "bool" => coerce_scalar(rest, PrimitiveType::Boolean, DEFAULT_GROUPING_SEPARATORS),
+        "str" => Ok(Value::String(rest.to_string())),
+        "json" => serde_json::from_str(rest).map_err(|e| e.to_string()),
+        _ => return Ok(None),
+    };
+    parsed.map(Some)
+}
 
-    let instance = Value::Object(config.clone());
+/// Joins a dotted parent path and a key.
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
 
-    match compiled_schema.validate(&instance) {
-        Ok(_) => Ok(config),
-        Err(errors) => {
-            if retried {
-                // Convert validation errors to a string
-                let error_messages: Vec<String> = errors.map(|e| e.to_string()).collect();
-                return Err(error_messages.join(", "));
-            }
-
-            let mut fixed_config = config.clone();
-            for error in errors {
-                // Collect all path chunks to build the full path
-                let mut path_parts: Vec<String> = Vec::new();
-                for path in error.instance_path.iter() {
-                    if let jsonschema::paths::PathChunk::Property(prop) = path {
-                        path_parts.push(prop.as_ref().to_string());
-                        continue;
-                    }
-                    if let jsonschema::paths::PathChunk::Index(idx) = path {
-                        path_parts.push(idx.to_string());
-                        continue;
-                    }
+/// Removes every object entry whose value is the string `sentinel` (e.g.
+/// `__unset__`) and returns the dotted paths that were removed. Callers remove
+/// these paths again after applying defaults so an explicitly unset key stays
+/// absent from the final config. Array elements set to `sentinel` are dropped
+/// from their array, moving later elements down, and reported at the index
+/// they were set at.
+pub fn take_unset_paths(config: &mut Map<String, Value>, sentinel: &str) -> Vec<String> {
+    fn take(map: &mut Map<String, Value>, sentinel: &str, path: &str, out: &mut Vec<String>) {
+        let keys: Vec<String> = map.keys().cloned().collect();
+        for key in keys {
+            let key_path = join_path(path, &key);
+            match map.get_mut(&key) {
+                Some(Value::String(value)) if value == sentinel => {
+                    map.remove(&key);
+                    out.push(key_path);
                 }
+                Some(value) => take_within(value, sentinel, &key_path, out),
+                None => {}
+            }
+        }
+    }
 
-                if let Some((last_part, parent_parts)) = path_parts.split_last() {
-                    let mut current = &mut fixed_config;
-                    let mut in_array = false;
-                    for (i, part) in parent_parts.iter().enumerate() {
-                        if in_array {
-                            in_array = false;
-                            continue;
-                        }
-
-                        current = current
-                            .get_mut(part)
-                            .and_then(|v| match v {
-                                Value::Object(map) => Some(map),
-                                Value::Array(arr) => {
-                                    if let Ok(index) = parent_parts[i + 1].parse::<usize>() {
-                                        if index < arr.len() {
-                                            if let Value::Object(map) = &mut arr[index] {
-                                                in_array = true;
-                                                return Some(map);
-                                            } else {
-                                                println!("Failed to get object at index {}", index);
-                                                return None;
-                                            }
-                                        } else {
-                                            println!("Index {} out of bounds", index);
-                                            return None;
-                                        }
-                                    }
-                                    None
-                                }
-                                _ => {
-                                    println!(
-                                        "Failed to get value at path {}",
-                                        path_parts.join(".")
-                                    );
-                                    None
-                                }
-                            })
-                            .unwrap();
+    fn take_within(value: &mut Value, sentinel: &str, path: &str, out: &mut Vec<String>) {
+        match value {
+            Value::Object(child) => take(child, sentinel, path, out),
+            Value::Array(items) => {
+                let mut index = 0;
+                items.retain_mut(|item| {
+                    let item_path = join_path(path, &index.to_string());
+                    index += 1;
+                    if item.as_str() == Some(sentinel) {
+                        out.push(item_path);
+                        false
+                    } else {
+                        take_within(item, sentinel, &item_path, out);
+                        true
                     }
+                });
+            }
+            _ => {}
+        }
+    }
 
-                    let existing = current.get(last_part.as_str()).cloned().unwrap();
-
-                    if let ValidationErrorKind::Type { kind } = &error.kind {
-                        match kind {
-                            TypeKind::Single(primitive_type) => {
-                                let new_value: Result<Value, String> = match existing {
-                                    Value::String(existing) => {
-                                        match primitive_type {
-                                            PrimitiveType::Array => {
-                                                // Split by spaces or commas and trim each item
-                                                let items: Vec<Value> = existing
-                                                    .split([' ', ','])
-                                                    .filter(|s| !s.is_empty())
-                                                    .map(|s| Value::String(s.trim().to_string()))
-                                                    .collect();
-                                                Ok(Value::Array(items))
-                                            }
-                                            PrimitiveType::Boolean => {
-                                                if let Ok(value) = existing.parse::<bool>() {
-                                                    Ok(Value::Bool(value))
-                                                } else {
-                                                    Err("Unsupported type: Boolean".to_string())
-                                                }
-                                            }
-                                            PrimitiveType::Integer => {
-                                                if let Ok(value) = existing.parse::<i64>() {
-                                                    Ok(Value::Number(value.into()))
-                                                } else {
-                                                    Err("Unsupported type: Integer".to_string())
-                                                }
-                                            }
-                                            PrimitiveType::Null => {
-                                                Err("Unsupported type: Null".to_string())
-                                            }
-                                            PrimitiveType::Number => {
-                                                if let Ok(value) =
-                                                    existing.parse::<serde_json::Number>()
-                                                {
-                                                    Ok(Value::Number(value))
-                                                } else {
-                                                    Err("Unsupported type: Number".to_string())
-                                                }
-                                            }
-                                            PrimitiveType::Object => {
-                                                Err("Unsupported type: Object".to_string())
-                                            }
-                                            PrimitiveType::String => {
-                                                Ok(Value::String(existing.clone()))
-                                            }
-                                        }
-                                    }
-                                    _ => Err(format!(
-                                        "Existing value is not a string: {:#?}",
-                                        existing
-                                    )),
-                                };
-                                current.insert(last_part.to_string(), new_value.unwrap());
-                            }
-                            _ => return Err(format!("Unsupported type: {:?}", error.kind)),
-                        }
-                    }
-                }
+    let mut out = Vec::new();
+    take(config, sentinel, "", &mut out);
+    out
+}
+
+/// Removes the value at a dotted object `path` from the config, returning it
+/// if it was present.
+pub fn remove_path(config: &mut Map<String, Value>, path: &str) -> Option<Value> {
+    let (parents, last) = match path.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, path),
+    };
+    let mut current = config;
+    for part in parents.into_iter().flat_map(|p| p.split('.')) {
+        current = current.get_mut(part)?.as_object_mut()?;
+    }
+    current.remove(last)
+}
+
+/// Sets every property annotated with `"x-env-presence-bool": true` based on
+/// whether an env var mapped onto its path: `true` if one did (whatever its
+/// value, even empty), otherwise the schema `default` or `false`. A flag no
+/// env var set keeps any value `config` already has for it, e.g. from a base
+/// config.
+pub fn apply_presence_flags(
+    schema: &Value,
+    properties: &BTreeMap<String, EnvProperty>,
+    config: &mut Map<String, Value>,
+) {
+    for mapping in schema_env_mapping("", schema) {
+        let parts: Vec<String> = mapping.path.split('.').map(str::to_string).collect();
+        let is_presence_flag = schema_at_path(schema, &parts)
+            .and_then(|s| s.get("x-env-presence-bool"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if !is_presence_flag {
+            continue;
+        }
+
+        let present = properties.values().any(|p| p.path == mapping.path);
+        if !present && value_at_path(config, &mapping.path).is_some() {
+            continue;
+        }
+        let value = match (present, mapping.default) {
+            (true, _) => Value::Bool(true),
+            (false, Some(default)) => default,
+            (false, None) => Value::Bool(false),
+        };
+        insert_path(config, &mapping.path, value);
+    }
+}
+
+/// Inserts `value` at a dotted object `path`, creating (or replacing non-object
+/// values with) intermediate objects as needed.
+fn insert_path(config: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((first, rest)) => {
+            let entry = config
+                .entry(first.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !entry.is_object() {
+                *entry = Value::Object(Map::new());
+            }
+            if let Value::Object(child) = entry {
+                insert_path(child, rest, value);
             }
-            Ok(fix_and_validate_json(schema, fixed_config, true)?)
+        }
+        None => {
+            config.insert(path.to_string(), value);
         }
     }
 }
 
-/// Recursively creates a nested JSON object based on the given `path` and sets the value
-/// to the given `value`.
-///
-/// The `path` is split by dots (`.`) and each part is used to create a nested JSON
-/// object. If the part is a number, it is used as an array index, otherwise it is used as
-/// a key in an object.
-///
-/// For example, if the `path` is `"a.b.0.c"`, the JSON object will look like this:
-///
-///
-pub fn create_nested_json(config: &mut Map<String, Value>, path: &str, value: &str) {
-    let parts: Vec<&str> = path.split('.').collect();
+/// A schema property together with the environment variable that populates it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvMapping {
+    pub env: String,
+    pub path: String,
+    pub property_type: String,
+    pub required: bool,
+    pub default: Option<Value>,
+}
+
+/// Converts a dotted config `path` back into the environment variable name
+/// that `process_env_vars` would map onto it. This is the reverse of the path
+/// transform: underscores inside a segment are doubled, segments are joined
+/// with single underscores and the result is uppercased and prefixed.
+pub fn path_to_env_var(prefix: &str, path: &str) -> String {
+    path_to_env_var_with_options(prefix, path, &EnvOptions::default())
+}
+
+/// Same as `path_to_env_var`, but reverses the transform configured by
+/// `options`: segments are joined with `options.nesting_separator` (doubled
+/// inside a segment), `options.prefix_delimiter` follows the prefix, segments
+/// that `options.key_map` maps onto are named by their env segment and, with
+/// `options.preserve_case`, segments keep their case.
+pub fn path_to_env_var_with_options(prefix: &str, path: &str, options: &EnvOptions) -> String {
+    let separator = options.nesting_separator.as_str();
+    let key = path
+        .split('.')
+        .map(|segment| {
+            let mapped = options
+                .key_map
+                .iter()
+                .find(|(_, key)| key.as_str() == segment)
+                .map(|(env_segment, _)| env_segment.clone());
+            let segment = match mapped {
+                Some(env_segment) => env_segment,
+                None if options.preserve_case => segment.to_string(),
+                None => segment.to_uppercase(),
+            };
+            segment.replace(separator, &separator.repeat(2))
+        })
+        .collect::<Vec<String>>()
+        .join(separator);
+    format!("{}{}{}", prefix, options.prefix_delimiter, key)
+}
+
+/// Walks the `properties` of a schema (following `$ref`s) and returns one
+/// `EnvMapping` per leaf property, describing the env var name, config path,
+/// declared type, whether the parent object requires it and its default.
+pub fn schema_env_mapping(prefix: &str, schema: &Value) -> Vec<EnvMapping> {
+    schema_env_mapping_with_options(prefix, schema, &EnvOptions::default())
+}
 
-    fn set_nested_value(map: &mut Map<String, Value>, parts: &[&str], value: &str) {
-        if parts.is_empty() {
+/// Same as `schema_env_mapping`, but names env vars with
+/// `path_to_env_var_with_options`.
+pub fn schema_env_mapping_with_options(
+    prefix: &str,
+    schema: &Value,
+    options: &EnvOptions,
+) -> Vec<EnvMapping> {
+    fn walk(
+        root: &Value,
+        schema: &Value,
+        prefix: &str,
+        path: &str,
+        options: &EnvOptions,
+        out: &mut Vec<EnvMapping>,
+    ) {
+        let schema = follow_ref(root, schema);
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
             return;
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        for (key, property) in properties {
+            let property = follow_ref(root, property);
+            let property_path = join_path(path, key);
+
+            if property.get("properties").is_some() {
+                walk(root, property, prefix, &property_path, options, out);
+                continue;
+            }
+
+            out.push(EnvMapping {
+                env: path_to_env_var_with_options(prefix, &property_path, options),
+                path: property_path,
+                property_type: schema_type_name(property),
+                required: required.contains(&key.as_str()),
+                default: property.get("default").cloned(),
+            });
         }
+    }
+
+    let mut out = Vec::new();
+    walk(schema, schema, prefix, "", options, &mut out);
+    out
+}
+
+/// Renders the schema to env var mapping as a Markdown table with the columns
+/// Env Var, Path, Type, Required and Default.
+pub fn mapping_markdown(prefix: &str, schema: &Value) -> String {
+    mapping_markdown_with_options(prefix, schema, &EnvOptions::default())
+}
+
+/// Same as `mapping_markdown`, but names env vars with
+/// `path_to_env_var_with_options`.
+pub fn mapping_markdown_with_options(prefix: &str, schema: &Value, options: &EnvOptions) -> String {
+    let mut out = String::from("| Env Var | Path | Type | Required | Default |\n");
+    out.push_str("| --- | --- | --- | --- | --- |\n");
+    for mapping in schema_env_mapping_with_options(prefix, schema, options) {
+        let default = mapping
+            .default
+            .map(|d| format!("`{}`", d))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "| `{}` | `{}` | {} | {} | {} |\n",
+            escape_markdown_cell(&mapping.env),
+            escape_markdown_cell(&mapping.path),
+            escape_markdown_cell(&mapping.property_type),
+            if mapping.required { "yes" } else { "no" },
+            escape_markdown_cell(&default)
+        ));
+    }
+    out
+}
 
-        let (first, rest) = parts.split_at(1);
-        let part = first[0];
+/// Escapes the pipes in a Markdown table cell, such as those of union types
+/// (`integer|null`), which would otherwise split it into columns.
+fn escape_markdown_cell(cell: &str) -> String {
+    cell.replace('|', "\\|")
+}
+
+/// Lists the env var names a schema accepts under `prefix`, walking its
+/// `properties` recursively (following `$ref`s) in the reverse of the
+/// `process_env_vars` transform. Objects with `properties` are only listed
+/// through their children, and maps (`additionalProperties`) through a
+/// representative `*` key. Arrays are listed by name, for inline lists, and
+/// per element: tuple positions by index and other items by a representative
+/// `*` segment, e.g. `PREFIX_SERVERS_*_HOST`.
+pub fn expected_env_vars(schema: &Value, prefix: &str) -> Vec<String> {
+    expected_env_vars_with_options(schema, prefix, &EnvOptions::default())
+}
+
+/// Same as `expected_env_vars`, but names env vars with
+/// `path_to_env_var_with_options`.
+pub fn expected_env_vars_with_options(
+    schema: &Value,
+    prefix: &str,
+    options: &EnvOptions,
+) -> Vec<String> {
+    fn walk(root: &Value, schema: &Value, path: &str, depth: usize, out: &mut Vec<String>) {
+        let schema = follow_ref(root, schema);
+        // Recursive `$ref`s would otherwise never bottom out
+        if depth > DEFAULT_MAX_DEPTH {
+            return;
+        }
 
-        if rest.is_empty() {
-            // Final value
-            map.insert(part.to_string(), Value::String(value.to_string()));
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let additional = schema
+            .get("additionalProperties")
+            .filter(|additional| additional.is_object());
+        if properties.is_some() || additional.is_some() {
+            for (key, property) in properties.into_iter().flatten() {
+                walk(root, property, &join_path(path, key), depth + 1, out);
+            }
+            if let Some(additional) = additional {
+                walk(root, additional, &join_path(path, "*"), depth + 1, out);
+            }
             return;
         }
 
-        let next = &rest[0];
-        let is_next_array_index = next.parse::<usize>().is_ok();
+        if !path.is_empty() {
+            out.push(path.to_string());
+        }
 
-        let entry = map.entry(part.to_string()).or_insert_with(|| {
-            if is_next_array_index {
-                Value::Array(Vec::new())
-            } else {
-                Value::Object(Map::new())
+        let tuple = schema
+            .get("prefixItems")
+            .or_else(|| schema.get("items"))
+            .and_then(Value::as_array);
+        match (tuple, schema.get("items")) {
+            (Some(tuple), _) => {
+                for (index, item) in tuple.iter().enumerate() {
+                    walk(
+                        root,
+                        item,
+                        &join_path(path, &index.to_string()),
+                        depth + 1,
+                        out,
+                    );
+                }
             }
-        });
+            (None, Some(items)) if items.is_object() => {
+                walk(root, items, &join_path(path, "*"), depth + 1, out);
+            }
+            _ => {}
+        }
+    }
 
-        match entry {
-            Value::Array(arr) => {
-                let idx = next.parse::<usize>().unwrap();
-                while arr.len() <= idx {
-                    if rest.len() == 1 {
-                        // If this is the last part, use the value directly
-                        arr.push(Value::String(value.to_string()));
-                    } else {
-                        arr.push(Value::Object(Map::new()));
-                    }
+    let mut paths = Vec::new();
+    walk(schema, schema, "", 0, &mut paths);
+    paths
+        .iter()
+        .map(|path| path_to_env_var_with_options(prefix, path, options))
+        .collect()
+}
+
+/// A config value that differs from the `default` declared for it in the schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefaultOverride {
+    pub path: String,
+    pub default: Value,
+    pub actual: Value,
+}
+
+/// Lists every schema property with a `default` whose value in `config`
+/// differs from that default. Properties missing from the config are not
+/// reported since they are not overridden.
+pub fn default_overrides(schema: &Value, config: &Map<String, Value>) -> Vec<DefaultOverride> {
+    schema_env_mapping("", schema)
+        .into_iter()
+        .filter_map(|mapping| {
+            let default = mapping.default?;
+            let actual = value_at_path(config, &mapping.path)?;
+            (*actual != default).then(|| DefaultOverride {
+                path: mapping.path,
+                default,
+                actual: actual.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Looks up the value at a dotted `path` in the config, where numeric
+/// segments index into arrays.
+fn value_at_path<'a>(config: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let (first, rest) = path.split_once('.').unwrap_or((path, ""));
+    let mut value = config.get(first)?;
+    for part in rest.split('.').filter(|part| !part.is_empty()) {
+        value = match value {
+            Value::Object(map) => map.get(part)?,
+            Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Returns the declared `type` of a schema as a display string, joining union
+/// types with `|`.
+fn schema_type_name(schema: &Value) -> String {
+    match schema.get("type") {
+        Some(Value::String(t)) => t.clone(),
+        Some(Value::Array(types)) => types
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<&str>>()
+            .join("|"),
+        _ => "any".to_string(),
+    }
+}
+
+/// Finds the subschema describing the value at `path` (a list of object keys and
+/// array indices) by walking `properties`, `additionalProperties` and `items`
+/// from the root schema and following `$ref`s along the way.
+fn schema_at_path<'a>(root: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let mut current = follow_ref(root, root);
+    for part in path {
+        current = child_schema(root, current, part)?;
+    }
+    Some(current)
+}
+
+/// Finds the subschema of `schema` describing its child `part` (an object key
+/// or array index) from its `properties`, `patternProperties`,
+/// `additionalProperties` or `items`, following a `$ref` on the result.
+fn child_schema<'a>(root: &'a Value, schema: &'a Value, part: &str) -> Option<&'a Value> {
+    let next = match (part.parse::<usize>(), schema.get("items")) {
+        (Ok(index), Some(Value::Array(items))) => items.get(index),
+        (Ok(_), Some(items)) => Some(items),
+        _ => schema
+            .get("properties")
+            .and_then(|p| p.get(part))
+            .or_else(|| pattern_property(schema, part))
+            .or_else(|| schema.get("additionalProperties").filter(|a| a.is_object())),
+    }?;
+    Some(follow_ref(root, next))
+}
+
+/// Finds the subschema of the first of `schema`'s `patternProperties` whose
+/// regex matches `key`. Patterns that don't compile are skipped.
+fn pattern_property<'a>(schema: &'a Value, key: &str) -> Option<&'a Value> {
+    schema
+        .get("patternProperties")?
+        .as_object()?
+        .iter()
+        .find(|(pattern, _)| Regex::new(pattern).is_ok_and(|regex| regex.is_match(key)))
+        .map(|(_, subschema)| subschema)
+}
+
+/// Follows a `$ref` on `schema`, and any chain of `$ref`s from there, if it
+/// resolves within `root`, otherwise returns `schema` unchanged.
+fn follow_ref<'a>(root: &'a Value, schema: &'a Value) -> &'a Value {
+    schema
+        .get("$ref")
+        .and_then(Value::as_str)
+        .and_then(|r| resolve_ref_chain(root, r))
+        .unwrap_or(schema)
+}
+
+/// The differences between two configs, keyed by dotted leaf path.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub added: BTreeMap<String, Value>,
+    pub removed: BTreeMap<String, Value>,
+    pub changed: BTreeMap<String, (Value, Value)>,
+}
+
+impl ConfigDiff {
+    /// Returns true if the two configs had identical leaves.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Flattens a config into its leaf values keyed by dotted path, using array
+/// indices as path segments (e.g. `servers.0.host`). Empty objects and arrays
+/// are kept as leaves so they are not lost.
+pub fn flatten_config(config: &Map<String, Value>) -> BTreeMap<String, Value> {
+    fn flatten(value: &Value, path: String, out: &mut BTreeMap<String, Value>) {
+        match value {
+            Value::Object(map) if !map.is_empty() => {
+                for (key, child) in map {
+                    flatten(child, join_path(&path, key), out);
                 }
-                if rest.len() > 1 {
-                    if let Value::Object(next_map) = &mut arr[idx] {
-                        set_nested_value(next_map, &rest[1..], value);
-                    }
+            }
+            Value::Array(items) if !items.is_empty() => {
+                for (index, child) in items.iter().enumerate() {
+                    flatten(child, join_path(&path, &index.to_string()), out);
                 }
             }
-            Value::Object(next_map) => {
-                set_nested_value(next_map, rest, value);
+            _ => {
+                out.insert(path, value.clone());
             }
-            _ => unreachable!(),
         }
     }
 
-    set_nested_value(config, &parts, value);
+    let mut out = BTreeMap::new();
+    for (key, value) in config {
+        flatten(value, key.clone(), &mut out);
+    }
+    out
 }
 
-/// Processes environment variables that start with a given prefix and
-/// returns a `HashMap` where each key is the original environment variable
-/// name, and each value is an `EnvProperty` containing:
-/// - `env`: the original environment variable name,
-/// - `value`: the value of the environment variable,
-/// - `path`: a transformed version of the key where double underscores (`__`)
-///   are replaced with underscores, underscores (`_`) are replaced with dots (`.`),
-///   and the whole path is converted to lowercase.
-///
-/// # Arguments
-///
-/// * `prefix` - A string slice that holds the prefix to filter environment variables.
-///
-/// # Returns
-///
-/// * `Result<HashMap<String, EnvProperty>, Box<dyn std::error::Error>>` - A result containing
-///   a `HashMap` of environment variables matching the prefix transformed into `EnvProperty`
-///   structs, or an error.
-pub fn process_env_vars(
+/// Maps a config back onto the environment variables that would produce it,
+/// as `(name, value)` pairs sorted by path. Strings are used as is and every
+/// other value is stringified as JSON.
+pub fn config_to_env(prefix: &str, config: &Map<String, Value>) -> Vec<(String, String)> {
+    config_to_env_with_options(prefix, config, &EnvOptions::default())
+}
+
+/// Same as `config_to_env`, but names env vars with
+/// `path_to_env_var_with_options`.
+pub fn config_to_env_with_options(
+    prefix: &str,
+    config: &Map<String, Value>,
+    options: &EnvOptions,
+) -> Vec<(String, String)> {
+    flatten_config(config)
+        .into_iter()
+        .map(|(path, value)| {
+            let value = match value {
+                Value::String(s) => s,
+                other => other.to_string(),
+            };
+            (path_to_env_var_with_options(prefix, &path, options), value)
+        })
+        .collect()
+}
+
+/// Computes the leaf-level differences going from config `old` to config `new`.
+pub fn diff_configs(old: &Map<String, Value>, new: &Map<String, Value>) -> ConfigDiff {
+    let old = flatten_config(old);
+    let mut new = flatten_config(new);
+    let mut diff = ConfigDiff::default();
+
+    for (path, old_value) in old {
+        match new.remove(&path) {
+            Some(new_value) if new_value != old_value => {
+                diff.changed.insert(path, (old_value, new_value));
+            }
+            Some(_) => {}
+            None => {
+                diff.removed.insert(path, old_value);
+            }
+        }
+    }
+    diff.added = new;
+    diff
+}
+
+/// Builds the validated config for two environment snapshots and returns the
+/// differences between them, which is useful for reviewing what a change in
+/// deployment variables does to the resulting config.
+pub fn config_diff<A, B>(
+    prefix: &str,
+    schema: &Value,
+    env_a: A,
+    env_b: B,
+) -> Result<ConfigDiff, Box<dyn std::error::Error>>
+where
+    A: IntoIterator<Item = (String, String)>,
+    B: IntoIterator<Item = (String, String)>,
+{
+    let config_a = build_config_from(schema, prefix, env_a)?;
+    let config_b = build_config_from(schema, prefix, env_b)?;
+    Ok(diff_configs(&config_a, &config_b))
+}
+
+/// Runs the whole env to config pipeline in one call: reads the env vars
+/// starting with `prefix`, builds the nested config from their paths, applies
+/// `x-env-presence-bool` flags and coerces and validates the result against
+/// `schema`. The lower-level functions remain available for finer control.
+pub fn build_config(schema: &Value, prefix: &str) -> Result<Map<String, Value>, FixError> {
+    build_config_from(schema, prefix, env::vars())
+}
+
+/// Same as `build_config`, but reads the variables from `vars` instead of the
+/// process environment.
+pub fn build_config_from<I>(
+    schema: &Value,
+    prefix: &str,
+    vars: I,
+) -> Result<Map<String, Value>, FixError>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    build_config_from_with_options(schema, prefix, vars, &EnvOptions::default())
+}
+
+/// Same as `build_config_from`, but maps env var names to paths with
+/// `options`. Paths are resolved against the schema the same way the CLI
+/// does, so both produce the same config for the same variables and options.
+pub fn build_config_from_with_options<I>(
+    schema: &Value,
     prefix: &str,
-) -> Result<HashMap<String, EnvProperty>, Box<dyn std::error::Error>> {
-    let mut result = HashMap::new();
+    vars: I,
+    options: &EnvOptions,
+) -> Result<Map<String, Value>, FixError>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let properties = crate::v2::process_env_vars_from_with_options(prefix, schema, vars, options)
+        .map_err(|e| FixError::InvalidEnv(e.to_string()))?;
+    let mut config = Map::new();
+    for props in properties.values() {
+        create_nested_json_for_schema(
+            &mut config,
+            schema,
+            &props.path,
+            &props.value,
+            &FixOptions::default(),
+        )
+        .map_err(FixError::InvalidEnv)?;
+    }
+    apply_presence_flags(schema, &properties, &mut config);
+    fix_and_validate_json(schema, config)
+}
 
-    let env_vars: Vec<(String, String)> = env::vars()
-        .filter(|(key, _)| key.starts_with(prefix))
+/// Returns a reason if a value looks like the result of an operator mistake:
+/// quotes left over after the outer pair was stripped, surrounding whitespace
+/// or control characters.
+pub fn suspicious_value(value: &str) -> Option<&'static str> {
+    let quoted = |q: char| value.len() >= 2 && value.starts_with(q) && value.ends_with(q);
+    if quoted('"') || quoted('\'') {
+        Some("value looks like it was quoted twice")
+    } else if value.trim() != value {
+        Some("value has leading or trailing whitespace")
+    } else if value
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+    {
+        Some("value contains control characters")
+    } else {
+        None
+    }
+}
+
+/// Checks every processed env var with `suspicious_value` and returns one
+/// warning per suspicious variable, sorted by variable name.
+pub fn suspicious_values(properties: &BTreeMap<String, EnvProperty>) -> Vec<String> {
+    let mut warnings: Vec<String> = properties
+        .values()
+        .filter_map(|props| {
+            suspicious_value(&props.value).map(|reason| format!("{}: {}", props.env, reason))
+        })
         .collect();
+    warnings.sort();
+    warnings
+}
 
-    for (key, raw_value) in env_vars {
-        let stripped_key = key.strip_prefix(prefix).unwrap_or(&key);
-        let path = stripped_key
-            .replace("__", "||||")
-            .split('_')
-            .collect::<Vec<&str>>()
-            .join(".")
-            .to_lowercase()
-            .replace("||||", "_");
-
-        // Remove quotes from the start and end of the value if present
-        let trimmed_value = raw_value.trim();
-        let value = match (trimmed_value.starts_with('"') && trimmed_value.ends_with('"'))
-            || (trimmed_value.starts_with('\'') && trimmed_value.ends_with('\''))
-        {
-            true => {
-                let len = trimmed_value.len();
-                if len >= 2 {
-                    trimmed_value[1..len - 1].to_string()
-                } else {
-                    trimmed_value.to_string()
+/// An env var whose config path has no corresponding property in the schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnmappedVar {
+    pub env: String,
+    pub path: String,
+}
+
+/// Returns the processed env vars whose path leaves the schema's `properties`,
+/// typically because the variable name has a typo. A path is unmapped when it
+/// reaches an object schema that lists `properties` without the next segment
+/// and has no object `additionalProperties`; paths into schemas that declare
+/// no properties (free-form objects) are accepted. `$ref`s are followed.
+pub fn unmapped_vars(
+    schema: &Value,
+    properties: &BTreeMap<String, EnvProperty>,
+) -> Vec<UnmappedVar> {
+    fn is_mapped(root: &Value, path: &str) -> bool {
+        let mut current = follow_ref(root, root);
+        for part in path.split('.') {
+            match child_schema(root, current, part) {
+                Some(next) => current = next,
+                None => return current.get("properties").is_none(),
+            }
+        }
+        true
+    }
+
+    properties
+        .values()
+        .filter(|props| !is_mapped(schema, &props.path))
+        .map(|props| UnmappedVar {
+            env: props.env.clone(),
+            path: props.path.clone(),
+        })
+        .collect()
+}
+
+/// Returns the env vars among `applied`, listed in the order they were applied
+/// to the config, whose values did not end up in the final `config`: those
+/// overwritten by a later var with the same path (e.g. from another prefix)
+/// and those whose path is missing from the config.
+pub fn dropped_vars<'a>(
+    applied: &'a [EnvProperty],
+    config: &Map<String, Value>,
+) -> Vec<&'a EnvProperty> {
+    applied
+        .iter()
+        .enumerate()
+        .filter(|(index, props)| {
+            applied[index + 1..]
+                .iter()
+                .any(|later| later.path == props.path)
+                || value_at_path(config, &props.path).is_none()
+        })
+        .map(|(_, props)| props)
+        .collect()
+}
+
+/// A `$ref` that does not resolve within its schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DanglingRef {
+    /// JSON Pointer to the schema object holding the `$ref`.
+    pub location: String,
+    pub reference: String,
+}
+
+/// Walks the whole schema collecting every local (`#...`) `$ref` that does not
+/// resolve with `resolve_ref`. External references are not checked.
+pub fn dangling_refs(schema: &Value) -> Vec<DanglingRef> {
+    fn walk(root: &Value, value: &Value, location: &str, out: &mut Vec<DanglingRef>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(reference)) = map.get("$ref") {
+                    let resolves = reference == "#" || resolve_ref(root, reference).is_some();
+                    if reference.starts_with('#') && !resolves {
+                        out.push(DanglingRef {
+                            location: format!("#{}", location),
+                            reference: reference.clone(),
+                        });
+                    }
+                }
+                for (key, child) in map {
+                    walk(root, child, &format!("{}/{}", location, key), out);
                 }
             }
-            false => raw_value.clone(),
-        };
+            Value::Array(items) => {
+                for (index, child) in items.iter().enumerate() {
+                    walk(root, child, &format!("{}/{}", location, index), out);
+                }
+            }
+            _ => {}
+        }
+    }
 
-        result.insert(
-            key.clone(),
-            EnvProperty {
-                env: key.clone(),
-                value,
-                path,
-            },
-        );
+    let mut out = Vec::new();
+    walk(schema, schema, "", &mut out);
+    out
+}
+
+/// Transforms an env var name (without its prefix) into a dotted path: single
+/// underscores (or `options.nesting_separator`) separate segments, double ones
+/// stand for a literal one, and segments are lowercased (unless
+/// `options.preserve_case` is set) or replaced by the exact key
+/// `options.key_map` maps them to.
+fn env_key_to_path(key: &str, options: &EnvOptions) -> String {
+    let separator = options.nesting_separator.as_str();
+    // Env var names can't hold NUL, so it marks the escaped separators
+    key.replace(&separator.repeat(2), "\0")
+        .split(separator)
+        .map(|segment| env_segment_to_key(segment.replace('\0', separator), options))
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+/// Turns a single env var name segment into a path segment: the key
+/// `options.key_map` maps it to, or else the segment lowercased, unless
+/// `options.preserve_case` is set.
+fn env_segment_to_key(segment: String, options: &EnvOptions) -> String {
+    match options.key_map.get(&segment.to_uppercase()) {
+        Some(mapped) => mapped.clone(),
+        None if options.preserve_case => segment,
+        None => segment.to_lowercase(),
     }
-    Ok(result)
 }
 
 /// Resolves a reference path within a JSON schema to retrieve the associated value.
@@ -308,7 +2824,8 @@ pub fn process_env_vars(
 /// and traverses the schema to locate the value specified by the reference path. The
 /// reference path should be formatted as a JSON Pointer, with components separated by
 /// slashes (`/`). If the reference path starts with a `#/`, this prefix will be removed
-/// before processing.
+/// before processing. Each component is percent-decoded and then unescaped as a JSON
+/// Pointer token (`~1` becomes `/` and `~0` becomes `~`).
 ///
 /// # Arguments
 ///
@@ -330,8 +2847,229 @@ pub fn resolve_ref<'a>(schema: &'a Value, ref_path: &str) -> Option<&'a Value> {
     // Start from the root and traverse
     let mut current = schema;
     for component in components {
-        current = current.get(component)?;
+        // Refs are URI fragments, so components may be percent-encoded, and
+        // JSON Pointer escapes `/` as `~1` and `~` as `~0`
+        let component = percent_decode(component)?
+            .replace("~1", "/")
+            .replace("~0", "~");
+        current = match current {
+            Value::Array(items) => items.get(component.parse::<usize>().ok()?)?,
+            _ => current.get(&component)?,
+        };
+    }
+
+    Some(current)
+}
+
+/// Returns a copy of `schema` with the documents its external `$ref`s point to
+/// (e.g. `common.json#/definitions/port`) loaded from `schema_dir` and bundled
+/// under `$defs`, keyed by their path relative to `schema_dir`. Every external
+/// ref, and every local ref inside a loaded document, is rewritten to point
+/// into the bundle, so the result only has local refs. Each file is loaded
+/// once. Refs in `schema` itself are relative to `schema_dir`, wherever the
+/// schema was read from, while refs of loaded documents are relative to the
+/// document's own directory. Files outside `schema_dir` are refused, and refs
+/// with a URI scheme such as `https://` are left alone.
+pub fn bundle_external_refs(schema: &Value, schema_dir: &Path) -> Result<Value, String> {
+    struct Bundler {
+        dir: PathBuf,
+        loaded: BTreeMap<String, Value>,
+    }
+
+    impl Bundler {
+        fn rewrite(
+            &mut self,
+            value: &mut Value,
+            key: Option<&str>,
+            dir: &Path,
+        ) -> Result<(), String> {
+            match value {
+                Value::Object(map) => {
+                    if let Some(Value::String(reference)) = map.get("$ref") {
+                        let rewritten = self.rewrite_ref(reference, key, dir)?;
+                        map.insert("$ref".to_string(), Value::String(rewritten));
+                    }
+                    for child in map.values_mut() {
+                        self.rewrite(child, key, dir)?;
+                    }
+                }
+                Value::Array(items) => {
+                    for child in items {
+                        self.rewrite(child, key, dir)?;
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn rewrite_ref(
+            &mut self,
+            reference: &str,
+            key: Option<&str>,
+            dir: &Path,
+        ) -> Result<String, String> {
+            let (file, fragment) = reference.split_once('#').unwrap_or((reference, ""));
+            let key = match (file, key) {
+                ("", None) => return Ok(reference.to_string()),
+                ("", Some(key)) => key.to_string(),
+                (file, _) if file.contains("://") => return Ok(reference.to_string()),
+                (file, _) => self.load(&dir.join(file))?,
+            };
+            let pointer_key = key.replace('~', "~0").replace('/', "~1");
+            Ok(format!("#/$defs/{}{}", pointer_key, fragment))
+        }
+
+        fn load(&mut self, path: &Path) -> Result<String, String> {
+            let path = path.canonicalize().map_err(|e| {
+                format!("Failed to load referenced schema {}: {}", path.display(), e)
+            })?;
+            let relative = path.strip_prefix(&self.dir).map_err(|_| {
+                format!(
+                    "Refusing to load {} from outside the schema directory {}",
+                    path.display(),
+                    self.dir.display()
+                )
+            })?;
+            let key = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if self.loaded.contains_key(&key) {
+                return Ok(key);
+            }
+            // Reserve the key first so documents referencing each other terminate
+            self.loaded.insert(key.clone(), Value::Null);
+
+            let content = std::fs::read_to_string(&path).map_err(|e| {
+                format!("Failed to load referenced schema {}: {}", path.display(), e)
+            })?;
+            let mut document: Value = serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid referenced schema {}: {}", path.display(), e))?;
+            let dir = path.parent().unwrap_or(&self.dir).to_path_buf();
+            self.rewrite(&mut document, Some(&key), &dir)?;
+            self.loaded.insert(key.clone(), document);
+            Ok(key)
+        }
+    }
+
+    let dir = schema_dir
+        .canonicalize()
+        .map_err(|e| format!("Invalid schema directory {}: {}", schema_dir.display(), e))?;
+    let mut bundler = Bundler {
+        dir: dir.clone(),
+        loaded: BTreeMap::new(),
+    };
+    let mut bundled = schema.clone();
+    bundler.rewrite(&mut bundled, None, &dir)?;
+
+    if !bundler.loaded.is_empty() {
+        let Value::Object(root) = &mut bundled else {
+            return Err("A schema with external $refs must be a JSON object".to_string());
+        };
+        let defs = root
+            .entry("$defs")
+            .or_insert_with(|| Value::Object(Map::new()));
+        let Value::Object(defs) = defs else {
+            return Err("The schema's $defs must be an object".to_string());
+        };
+        defs.extend(bundler.loaded);
     }
+    Ok(bundled)
+}
 
+/// Like `resolve_ref`, but when the target is itself a `$ref` keeps following
+/// the chain until it reaches a schema without one. Returns `None` if any ref
+/// in the chain does not resolve, or if the chain loops back on itself.
+pub fn resolve_ref_chain<'a>(schema: &'a Value, ref_path: &str) -> Option<&'a Value> {
+    let mut seen = vec![ref_path];
+    let mut current = resolve_ref(schema, ref_path)?;
+    while let Some(next) = current.get("$ref").and_then(Value::as_str) {
+        if seen.contains(&next) {
+            return None;
+        }
+        seen.push(next);
+        current = resolve_ref(schema, next)?;
+    }
     Some(current)
 }
+
+/// Decodes `%XX` escapes in a URI fragment. Returns `None` if an escape is
+/// malformed or the decoded bytes are not UTF-8.
+fn percent_decode(value: &str) -> Option<String> {
+    if !value.contains('%') {
+        return Some(value.to_string());
+    }
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            // from_str_radix would accept a sign, as in `%+1`
+            let hex = bytes.get(index + 1..index + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coercion_cache_matches_coerce_scalar() {
+        let inputs = [
+            "1", "1_000", "1,000", "abc", " 8080\n", "2.5", "null", "yes",
+        ];
+        let types = [
+            PrimitiveType::Boolean,
+            PrimitiveType::Integer,
+            PrimitiveType::Null,
+            PrimitiveType::Number,
+            PrimitiveType::String,
+        ];
+
+        for separators in [DEFAULT_GROUPING_SEPARATORS.to_vec(), vec![',']] {
+            let options = FixOptions {
+                grouping_separators: separators.clone(),
+                ..FixOptions::default()
+            };
+            let mut cache = CoercionCache::with_options(&options);
+            // The second pass is answered from the cache
+            for _ in 0..2 {
+                for input in inputs {
+                    for primitive_type in types {
+                        assert_eq!(
+                            cache.coerce(input, primitive_type),
+                            coerce_scalar(input, primitive_type, &separators),
+                            "{:?} as {:?} with {:?}",
+                            input,
+                            primitive_type,
+                            separators
+                        );
+                    }
+                }
+            }
+        }
+
+        // The separators a cache was created with decide its results
+        let mut default_cache = CoercionCache::with_options(&FixOptions::default());
+        let mut comma_cache = CoercionCache::with_options(&FixOptions {
+            grouping_separators: vec![','],
+            ..FixOptions::default()
+        });
+        assert_ne!(
+            default_cache.coerce("1_000", PrimitiveType::Integer),
+            comma_cache.coerce("1_000", PrimitiveType::Integer)
+        );
+    }
+}
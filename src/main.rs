@@ -1,24 +1,458 @@
 use clap::Parser;
-use env_to_schema_json::{create_nested_json, fix_and_validate_json, process_env_vars};
+use env_to_schema_json::{
+    ArraySplit, DEFAULT_MAX_DEPTH, EnvOptions, EnvProperty, FixError, FixOptions, apply_defaults,
+    apply_presence_flags, apply_type_hints, bundle_external_refs, config_to_env_with_options,
+    create_nested_json_for_schema, dangling_refs, deep_merge, default_overrides, diff_configs,
+    dropped_vars, failing_paths_with_options, fix_and_validate_json_with_report, flatten_config,
+    mapping_markdown_with_options, missing_required, parse_dotenv, path_to_env_var_with_options,
+    prune_to_paths, quote_env_value, remove_path, suspicious_values, take_unset_paths,
+    unmapped_vars, v2,
+};
+use regex::Regex;
 use serde_json::Map;
 use serde_json::Value;
-use std::io::Read;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{IsTerminal, Read};
+use std::path::PathBuf;
+
+/// Serialization formats supported for the generated config.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Json,
+    /// An array of `{"name": ..., "value": ...}` env var entries
+    EnvJson,
+    Yaml,
+    Toml,
+}
+
+/// JSON Schema drafts the schema can be compiled with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum SchemaDraft {
+    Draft7,
+    Draft2019,
+    Draft2020,
+}
+
+impl From<SchemaDraft> for jsonschema::Draft {
+    fn from(draft: SchemaDraft) -> Self {
+        match draft {
+            SchemaDraft::Draft7 => jsonschema::Draft::Draft7,
+            SchemaDraft::Draft2019 => jsonschema::Draft::Draft201909,
+            SchemaDraft::Draft2020 => jsonschema::Draft::Draft202012,
+        }
+    }
+}
+
+/// Documentation formats that can be generated from the schema.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum DocsFormat {
+    Markdown,
+}
+
+/// Where env vars are selected from: names starting with a `--prefix`, or
+/// names matching `--prefix-regex`.
+#[derive(Clone, Copy)]
+enum EnvSource<'a> {
+    Prefix(&'a str),
+    Pattern(&'a Regex),
+}
+
+/// A single `--emit` target: a format and where to write it (`None` is stdout).
+#[derive(Clone, Debug)]
+struct EmitTarget {
+    format: OutputFormat,
+    path: Option<PathBuf>,
+}
+
+/// Parses an emit spec of the form `<format>:<path>`, where a path of `-` means stdout.
+fn parse_emit_target(spec: &str) -> Result<EmitTarget, String> {
+    let (format, target) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid emit target '{}', expected <format>:<path>", spec))?;
+    let format = <OutputFormat as clap::ValueEnum>::from_str(format, true)
+        .map_err(|_| format!("Unknown output format '{}'", format))?;
+    let path = match target {
+        "-" => None,
+        "" => return Err(format!("Missing path in emit target '{}'", spec)),
+        path => Some(PathBuf::from(path)),
+    };
+    Ok(EmitTarget { format, path })
+}
+
+/// Parses a `--set` override of the form `<path>=<value>`.
+fn parse_set(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((path, value)) if !path.is_empty() => Ok((path.to_string(), value.to_string())),
+        _ => Err(format!(
+            "Invalid override '{}', expected <path>=<value>",
+            spec
+        )),
+    }
+}
+
+/// Quotes a value for a POSIX shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Runs `command` with `vars` added to its environment. On unix the process is
+/// replaced by the command; elsewhere it is waited for and its exit code passed on.
+fn exec_with_env(
+    command: &[String],
+    vars: Vec<(String, String)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (program, args) = command.split_first().ok_or("No command given")?;
+    let mut child = std::process::Command::new(program);
+    child.args(args).envs(vars);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let error = child.exec();
+        Err(format!("Failed to run {}: {}", program, error).into())
+    }
+    #[cfg(not(unix))]
+    {
+        let status = child
+            .status()
+            .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+        std::process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Splits stdin holding several JSON documents (separated by whitespace or NUL
+/// bytes) into the text of each document.
+fn split_documents(content: &str) -> Result<Vec<String>, serde_json::Error> {
+    let content = content.replace('\0', "\n");
+    serde_json::Deserializer::from_str(&content)
+        .into_iter::<Value>()
+        .map(|document| document.map(|d| d.to_string()))
+        .collect()
+}
+
+/// Reads the schema from a file, or fetches it if `location` is an `http://`
+/// or `https://` URL.
+fn read_schema(location: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return fetch_schema(location);
+    }
+    std::fs::read_to_string(location)
+        .map_err(|e| format!("Failed to read the schema {}: {}", location, e).into())
+}
+
+/// Timeout for fetching a schema from a URL, covering the whole request.
+#[cfg(feature = "remote-schema")]
+const SCHEMA_FETCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Fetches the schema at `url`, failing on anything but a 200 response.
+#[cfg(feature = "remote-schema")]
+fn fetch_schema(url: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(SCHEMA_FETCH_TIMEOUT)
+        .build();
+    let response = match agent.get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => {
+            return Err(format!("Failed to fetch the schema from {}: HTTP {}", url, status).into());
+        }
+        Err(error) => {
+            return Err(format!("Failed to fetch the schema from {}: {}", url, error).into());
+        }
+    };
+    if response.status() != 200 {
+        return Err(format!(
+            "Failed to fetch the schema from {}: HTTP {}",
+            url,
+            response.status()
+        )
+        .into());
+    }
+    response
+        .into_string()
+        .map_err(|e| format!("Failed to read the schema from {}: {}", url, e).into())
+}
+
+#[cfg(not(feature = "remote-schema"))]
+fn fetch_schema(url: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Err(format!(
+        "Cannot fetch the schema from {}: built without the remote-schema feature",
+        url
+    )
+    .into())
+}
+
+/// Parses a schema document read from `source`, tolerating a leading UTF-8
+/// BOM and surrounding whitespace as written by some Windows tools.
+fn parse_schema(content: &str, source: &str) -> Result<Value, String> {
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content).trim();
+    serde_json::from_str(content)
+        .map_err(|e| format!("Failed to parse the schema from {} as JSON: {}", source, e))
+}
+
+/// Serializes the config in the requested format. `prefix` and `env_options`
+/// are used to name the env vars of formats that map the config back onto the
+/// environment.
+fn render(
+    config: &Map<String, Value>,
+    format: OutputFormat,
+    prefix: &str,
+    env_options: &EnvOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(&Value::Object(
+            config.clone(),
+        ))?),
+        OutputFormat::EnvJson => {
+            let entries: Vec<Value> = config_to_env_with_options(prefix, config, env_options)
+                .into_iter()
+                .map(|(name, value)| serde_json::json!({"name": name, "value": value}))
+                .collect();
+            Ok(serde_json::to_string_pretty(&entries)?)
+        }
+        // serde_yaml ends documents with a newline, which callers add themselves
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(config)?.trim_end().to_string()),
+        OutputFormat::Toml => {
+            if let Some((path, _)) = flatten_config(config)
+                .into_iter()
+                .find(|(_, v)| v.is_null())
+            {
+                return Err(format!("TOML cannot represent the null value at '{}'", path).into());
+            }
+            Ok(toml::to_string(config)?.trim_end().to_string())
+        }
+    }
+}
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Prefix to filter environment variables
+    /// Prefix to filter environment variables. May be repeated to merge several
+    /// prefixes, later ones overriding earlier ones; the first names env vars
+    /// in generated output
     #[arg(short, long, default_value = "PREFIX_")]
-    prefix: String,
+    prefix: Vec<String>,
+
+    /// Select env vars by a regex instead of a prefix. The named group `rest`
+    /// (or the first group) is mapped to the path, after the values of any
+    /// other named groups, e.g. ^SVC(?P<idx>\d+)_(?P<rest>.+)$. Env var names
+    /// can't be written out without a prefix, so it rules out --export-env and
+    /// env-json output
+    #[arg(
+        long,
+        value_name = "REGEX",
+        value_parser = Regex::new,
+        conflicts_with_all = ["prefix", "export_env"]
+    )]
+    prefix_regex: Option<Regex>,
 
+    /// Delimiter between the prefix and the rest of env var names, e.g. `__`
+    /// for APP__DATABASE_HOST with --prefix APP
+    #[arg(long, value_name = "DELIMITER", default_value = "")]
+    prefix_delimiter: String,
+
+    /// Separator between path segments in env var names; doubling it stands
+    /// for a literal one
+    #[arg(
+        long,
+        value_name = "SEPARATOR",
+        default_value = "_",
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    nesting_separator: String,
+
+    /// Print env var paths and the config before coercion, and log diagnostics, to stderr
     #[arg(short, long)]
     debug: bool,
 
+    /// Schema file, or `-` for stdin. When omitted, the schema is read from
+    /// stdin if it is piped; without one, the env vars are output as the
+    /// nested config of strings they map to, without coercion or validation
     #[arg(short, long, default_value = "")]
     schema: String,
+
+    /// Directory to load files referenced by external $refs (e.g. common.json#/...) from
+    #[arg(long, value_name = "PATH")]
+    schema_dir: Option<PathBuf>,
+
+    /// JSON Schema draft to compile the schema with, instead of detecting it from $schema
+    #[arg(long, value_enum)]
+    draft: Option<SchemaDraft>,
+
+    /// Format of the config printed to stdout when no --emit target is given
+    #[arg(long, alias = "output-format", value_enum, default_value_t = OutputFormat::Json)]
+    format: OutputFormat,
+
+    /// Write the config to this file instead of stdout, in addition to any --emit targets
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Attach the original, uncoerced env values to the output keyed by path
+    #[arg(long)]
+    include_raw: bool,
+
+    /// Key under which the raw values are attached when using --include-raw
+    #[arg(long, default_value = "_raw")]
+    raw_key: String,
+
+    /// Output target as <format>:<path>, where a path of `-` is stdout (repeatable)
+    #[arg(long = "emit", value_name = "FORMAT:PATH", value_parser = parse_emit_target)]
+    emit: Vec<EmitTarget>,
+
+    /// Print documentation of the env vars the schema accepts instead of a config
+    #[arg(long, value_enum)]
+    docs: Option<DocsFormat>,
+
+    /// Fill in properties no env var sets with their schema defaults
+    #[arg(long)]
+    apply_defaults: bool,
+
+    /// Env value that removes its path from the final config, even if it has a default
+    #[arg(long, default_value = "__unset__")]
+    unset_sentinel: String,
+
+    /// Maximum number of path segments an env var may expand to
+    #[arg(long, default_value_t = DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+
+    /// Print the paths whose values differ from their schema defaults to stderr
+    #[arg(long)]
+    show_overrides: bool,
+
+    /// Coerce and validate a JSON config document instead of env vars. A value of
+    /// `-` reads it from stdin; if the schema is also read from stdin, stdin must
+    /// hold the schema followed by the config, separated by a newline or NUL byte
+    #[arg(long, value_name = "PATH")]
+    config: Option<String>,
+
+    /// On validation failure, print only the subtrees containing failing paths
+    #[arg(long)]
+    emit_failed: bool,
+
+    /// Warn about values that look accidentally quoted, padded or contain control characters
+    #[arg(long)]
+    warn_suspicious: bool,
+
+    /// Treat warnings as errors, and reject env vars that map to no schema property
+    #[arg(long)]
+    strict: bool,
+
+    /// Honor inline type hints in values such as `int:42`, `bool:true` or `json:{...}`
+    #[arg(long)]
+    inline_type_hints: bool,
+
+    /// Check that every local $ref in the schema resolves before running
+    #[arg(long)]
+    check_refs: bool,
+
+    /// JSON file mapping env segments to exact schema keys, e.g. {"OAUTH2": "oAuth2"}
+    #[arg(long, value_name = "PATH")]
+    key_map: Option<PathBuf>,
+
+    /// Keep the case of env var segments instead of lowercasing them
+    #[arg(long)]
+    preserve_case: bool,
+
+    /// Also match runs of env var segments to camelCase keys by their
+    /// snake_case form (e.g. MAX_CONNS to maxConns)
+    #[arg(long)]
+    match_snake_case: bool,
+
+    /// Skip env vars whose full name matches this name or glob, e.g.
+    /// PREFIX_INTERNAL_* (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    ignore: Vec<String>,
+
+    /// How to split strings into arrays when the schema has no x-delimiter:
+    /// space, comma, both, none or a single character
+    #[arg(long, value_name = "MODE", default_value = "both")]
+    array_split: ArraySplit,
+
+    /// Replace numbers outside the schema's minimum/maximum with the bound they cross
+    #[arg(long)]
+    clamp: bool,
+
+    /// Validate the env values as strings, without coercing them, and report
+    /// every type mismatch
+    #[arg(long, conflicts_with = "clamp")]
+    no_coerce: bool,
+
+    /// Read env vars from a dotenv file; variables set in the environment take precedence
+    #[arg(long, value_name = "PATH")]
+    env_file: Option<PathBuf>,
+
+    /// JSON config file the env-derived config is deep-merged over
+    #[arg(long, value_name = "PATH")]
+    base: Option<PathBuf>,
+
+    /// Set a dotted config path to a value, overriding env vars (repeatable)
+    #[arg(long = "set", value_name = "PATH=VALUE", value_parser = parse_set)]
+    set: Vec<(String, String)>,
+
+    /// Write a JSON array recording every value coerced from its env string
+    /// to this file, for auditing
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Report the env vars whose values did not end up in the final config,
+    /// e.g. because another var or --set overrode the same path
+    #[arg(long)]
+    list_unmapped: bool,
+
+    /// Only check that the env vars satisfy the schema: print nothing to stdout, and
+    /// `OK` to stderr on success or the validation errors with a nonzero exit status on failure
+    #[arg(long)]
+    check: bool,
+
+    /// Print the added, removed and changed paths of the generated config
+    /// relative to this JSON config file instead of the config, exiting nonzero
+    /// if they differ
+    #[arg(long, value_name = "PATH")]
+    diff: Option<PathBuf>,
+
+    /// Export the validated config as env vars to the command given after `--`,
+    /// or print them as shell `export` statements if there is none
+    #[arg(long)]
+    export_env: bool,
+
+    /// Command to run with the exported env vars (requires --export-env)
+    #[arg(last = true, value_name = "COMMAND", requires = "export_env")]
+    command: Vec<String>,
+}
+
+/// Exit status for a config that doesn't satisfy the schema.
+const EXIT_VALIDATION_FAILED: i32 = 1;
+/// Exit status for every other error, e.g. an unreadable or invalid schema.
+const EXIT_ERROR: i32 = 2;
+
+/// The env vars (or config) don't satisfy the schema, as opposed to the tool
+/// failing to run. Exits with `EXIT_VALIDATION_FAILED`.
+#[derive(Debug)]
+struct ValidationFailed(String);
+
+impl std::fmt::Display for ValidationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-/// Main function that processes environment variables and validates them against a JSON schema.
+impl std::error::Error for ValidationFailed {}
+
+/// Runs the tool and reports any error on stderr, exiting with
+/// `EXIT_VALIDATION_FAILED` if the config doesn't satisfy the schema and with
+/// `EXIT_ERROR` for every other failure.
+fn main() {
+    if let Err(error) = run(Args::parse()) {
+        eprintln!("error: {}", error);
+        let code = if error.is::<ValidationFailed>() {
+            EXIT_VALIDATION_FAILED
+        } else {
+            EXIT_ERROR
+        };
+        std::process::exit(code);
+    }
+}
+
+/// Processes environment variables and validates them against a JSON schema.
 ///
 /// This function takes a prefix to filter environment variables, a boolean flag to enable
 /// debug mode, and a string path to a JSON schema file. It processes the environment variables
@@ -33,44 +467,429 @@ struct Args {
 ///
 /// # Returns
 ///
-/// * `Result<(), Box<dyn std::error::Error>>` - A result containing either an empty tuple or an error.
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+/// * `Result<(), Box<dyn std::error::Error>>` - A result containing either an empty tuple or an
+///   error, which is a `ValidationFailed` if the config doesn't satisfy the schema.
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    // The prefix used for env var names the tool writes out
+    let prefix = args.prefix[0].clone();
+    let env_json = args.format == OutputFormat::EnvJson
+        || args
+            .emit
+            .iter()
+            .any(|target| target.format == OutputFormat::EnvJson);
+    if args.prefix_regex.is_some() && env_json {
+        return Err(
+            "env-json output names env vars by --prefix and cannot be used with --prefix-regex"
+                .into(),
+        );
+    }
+
+    // Diagnostics go to stderr, and only when asked for, to keep stdout parseable
+    let default_filter = if args.debug { "debug" } else { "off" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+        .init();
+
+    let schema_omitted = args.schema.is_empty();
+    let schema_from_stdin =
+        args.schema == "-" || (schema_omitted && !std::io::stdin().is_terminal());
+    let config_from_stdin = args.config.as_deref() == Some("-");
 
-    let mut schema_content = String::new();
+    let mut stdin_content = String::new();
+    if schema_from_stdin || config_from_stdin {
+        std::io::stdin().read_to_string(&mut stdin_content)?;
+    }
+
+    let (schema_content, config_document) = match (schema_from_stdin, config_from_stdin) {
+        (true, true) => {
+            let mut documents = split_documents(&stdin_content)?.into_iter();
+            let schema = documents.next().unwrap_or_default();
+            let config = documents
+                .next()
+                .ok_or("Expected a schema and a config document on stdin")?;
+            (schema, Some(config))
+        }
+        (true, false) => (stdin_content, None),
+        (false, true) if schema_omitted => (String::new(), Some(stdin_content)),
+        (false, true) => (read_schema(&args.schema)?, Some(stdin_content)),
+        (false, false) if schema_omitted => (String::new(), None),
+        (false, false) => (read_schema(&args.schema)?, None),
+    };
+    let config_document = match (config_document, &args.config) {
+        (Some(document), _) => Some(document),
+        (None, Some(path)) => Some(std::fs::read_to_string(path)?),
+        (None, None) => None,
+    };
 
-    if args.schema.is_empty() {
-        // Read and parse the schema from stdin
-        std::io::stdin().read_to_string(&mut schema_content)?;
+    let mut schema = if schema_content.trim().is_empty() {
+        if !schema_omitted {
+            return Err("Pipe schema from stdin or provide a schema file".into());
+        }
+        // An empty schema accepts the config as is, leaving every value a string
+        log::info!("No schema given, skipping coercion and validation");
+        Value::Object(Map::new())
     } else {
-        // Read and parse the schema from file
-        schema_content = std::fs::read_to_string(args.schema)?;
+        let schema_source = if schema_from_stdin {
+            "stdin".to_string()
+        } else {
+            args.schema.clone()
+        };
+        parse_schema(&schema_content, &schema_source)?
+    };
+    if let Some(dir) = &args.schema_dir {
+        schema = bundle_external_refs(&schema, dir)?;
+    }
+
+    if args.check_refs {
+        let dangling = dangling_refs(&schema);
+        for entry in &dangling {
+            eprintln!(
+                "error: $ref '{}' at {} does not resolve",
+                entry.reference, entry.location
+            );
+        }
+        if !dangling.is_empty() {
+            return Err(format!("{} dangling $ref(s) in schema", dangling.len()).into());
+        }
     }
 
-    if schema_content.is_empty() {
-        return Err("Pipe schema from stdin or provide a schema file".into());
+    let key_map: HashMap<String, String> = match &args.key_map {
+        Some(path) => {
+            serde_json::from_str::<HashMap<String, String>>(&std::fs::read_to_string(path)?)?
+                .into_iter()
+                .map(|(segment, key)| (segment.to_uppercase(), key))
+                .collect()
+        }
+        None => HashMap::new(),
+    };
+    let env_options = EnvOptions {
+        max_depth: args.max_depth,
+        key_map,
+        preserve_case: args.preserve_case,
+        match_snake_case: args.match_snake_case,
+        ignore: args.ignore.clone(),
+        prefix_delimiter: args.prefix_delimiter.clone(),
+        nesting_separator: args.nesting_separator.clone(),
+    };
+
+    if let Some(DocsFormat::Markdown) = args.docs {
+        print!(
+            "{}",
+            mapping_markdown_with_options(&prefix, &schema, &env_options)
+        );
+        return Ok(());
     }
 
-    let schema: Value = serde_json::from_str(&schema_content)?;
+    let fix_options = FixOptions {
+        array_split: args.array_split,
+        clamp: args.clamp,
+        coerce: !args.no_coerce,
+        draft: args.draft.map(Into::into),
+        ..FixOptions::default()
+    };
+    let (mut config, result, applied) = match &config_document {
+        Some(document) => match serde_json::from_str(document)? {
+            Value::Object(config) => (config, BTreeMap::new(), Vec::new()),
+            _ => return Err("The config document must be a JSON object".into()),
+        },
+        None => {
+            let mut vars = match &args.env_file {
+                // The file's values are already unquoted, so keep them as is
+                Some(path) => parse_dotenv(&std::fs::read_to_string(path)?)?
+                    .into_iter()
+                    .map(|(key, value)| (key, quote_env_value(&value)))
+                    .collect(),
+                None => Vec::new(),
+            };
+            // Later entries win, so the real environment overrides the file
+            vars.extend(std::env::vars());
+            // Prefixes are applied in argument order, so later ones override
+            // earlier ones where their paths collide
+            let mut result = BTreeMap::new();
+            let mut applied = Vec::new();
+            let mut config = Map::new();
+            let sources: Vec<EnvSource> = match &args.prefix_regex {
+                Some(pattern) => vec![EnvSource::Pattern(pattern)],
+                None => args.prefix.iter().map(|p| EnvSource::Prefix(p)).collect(),
+            };
+            for source in sources {
+                let vars = vars.clone();
+                // Paths take the spelling of the schema keys they match, and
+                // keys matching patternProperties take their pattern's type
+                let properties = match source {
+                    EnvSource::Prefix(prefix) => {
+                        v2::process_env_vars_from_with_options(prefix, &schema, vars, &env_options)?
+                    }
+                    EnvSource::Pattern(pattern) => {
+                        v2::process_env_vars_matching_from(pattern, &schema, vars, &env_options)?
+                    }
+                };
+                for props in properties.values() {
+                    create_nested_json_for_schema(
+                        &mut config,
+                        &schema,
+                        &props.path,
+                        &props.value,
+                        &fix_options,
+                    )?;
+                }
+                applied.extend(properties.values().cloned());
+                result.extend(properties);
+            }
+            (config, result, applied)
+        }
+    };
 
-    let result = process_env_vars(&args.prefix)?;
+    if let Some(path) = &args.base {
+        let mut base = match serde_json::from_str(&std::fs::read_to_string(path)?)? {
+            Value::Object(base) => base,
+            _ => {
+                return Err(
+                    format!("The base config {} must be a JSON object", path.display()).into(),
+                );
+            }
+        };
+        deep_merge(&mut base, config);
+        config = base;
+    }
 
-    let mut config = Map::new();
+    // Presence flags describe the env vars, so a config document has none,
+    // and flags no env var set keep the value of the base config
+    if config_document.is_none() {
+        apply_presence_flags(&schema, &result, &mut config);
+    }
 
-    for (_env_var, props) in result {
-        create_nested_json(&mut config, &props.path, &props.value);
+    // --set replaces whatever the env vars or the base config set at its path
+    // rather than merging with it
+    for (path, value) in &args.set {
+        remove_path(&mut config, path);
+        create_nested_json_for_schema(&mut config, &schema, path, value, &fix_options)?;
     }
 
+    // Printed before any check can fail, as a path that didn't map is what
+    // --debug is usually needed for
     if args.debug {
-        println!(
+        for props in result.values() {
+            eprintln!("{} -> {}", props.env, props.path);
+        }
+    }
+
+    if args.warn_suspicious || args.strict {
+        let warnings = suspicious_values(&result);
+        for warning in &warnings {
+            eprintln!("warning: {}", warning);
+        }
+        if args.strict && !warnings.is_empty() {
+            return Err(ValidationFailed(
+                "Suspicious env values found and --strict is set".to_string(),
+            )
+            .into());
+        }
+    }
+
+    if args.strict {
+        let unmapped = unmapped_vars(&schema, &result);
+        for var in &unmapped {
+            eprintln!(
+                "error: {} maps to '{}', which is not a property in the schema",
+                var.env, var.path
+            );
+        }
+        if !unmapped.is_empty() {
+            return Err(ValidationFailed(format!(
+                "{} env var(s) do not match the schema and --strict is set",
+                unmapped.len()
+            ))
+            .into());
+        }
+    }
+
+    if args.inline_type_hints {
+        apply_type_hints(&mut config)?;
+    }
+
+    let unset = take_unset_paths(&mut config, &args.unset_sentinel);
+    if args.apply_defaults {
+        apply_defaults(&schema, &mut config);
+        // Unset paths stay unset even when the schema has a default for them
+        for path in &unset {
+            remove_path(&mut config, path);
+        }
+    }
+
+    if args.debug {
+        eprintln!(
             "ENV JSON: {}",
             serde_json::to_string_pretty(&Value::Object(config.clone()))?
         );
     }
 
-    let validated_config = fix_and_validate_json(&schema, config.clone(), false)?;
-    let config_json = serde_json::to_string_pretty(&Value::Object(validated_config))?;
-    println!("{}", config_json);
+    let missing = missing_required(&schema, &config);
+    if !missing.is_empty() {
+        for path in &missing {
+            // A --prefix-regex gives no prefix to name the env var with
+            match args.prefix_regex {
+                Some(_) => eprintln!("error: missing required field '{}'", path),
+                None => eprintln!(
+                    "error: missing required field '{}' (set {})",
+                    path,
+                    path_to_env_var_with_options(&prefix, path, &env_options)
+                ),
+            }
+        }
+        return Err(
+            ValidationFailed(format!("{} required field(s) missing", missing.len())).into(),
+        );
+    }
+
+    let (mut validated_config, coercions) =
+        match fix_and_validate_json_with_report(&schema, config.clone(), &fix_options) {
+            Ok(fixed) => fixed,
+            // A schema that doesn't compile or env vars that don't form a
+            // config are errors running the tool, not a failed validation
+            Err(error @ (FixError::SchemaCompile(_) | FixError::InvalidEnv(_))) => {
+                return Err(error.into());
+            }
+            Err(error) => {
+                if args.emit_failed {
+                    let failed = prune_to_paths(
+                        &config,
+                        &failing_paths_with_options(&schema, &config, &fix_options),
+                    );
+                    println!("{}", serde_json::to_string_pretty(&Value::Object(failed))?);
+                }
+                return Err(ValidationFailed(error.to_string()).into());
+            }
+        };
+
+    if let Some(path) = &args.report {
+        let records: Vec<Value> = coercions
+            .iter()
+            .map(|coercion| {
+                // Values from --set or --base have no env var
+                let env_var = result
+                    .values()
+                    .find(|props| props.path == coercion.path)
+                    .map(|props| props.env.clone());
+                serde_json::json!({
+                    "path": coercion.path,
+                    "env_var": env_var,
+                    "original_string": coercion.original,
+                    "coerced_value": coercion.value,
+                    "target_type": coercion.target_type,
+                })
+            })
+            .collect();
+        std::fs::write(
+            path,
+            format!("{}\n", serde_json::to_string_pretty(&records)?),
+        )
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+
+    if args.show_overrides {
+        for entry in default_overrides(&schema, &validated_config) {
+            eprintln!(
+                "{}: default {} -> actual {}",
+                entry.path, entry.default, entry.actual
+            );
+        }
+    }
+
+    if args.list_unmapped {
+        // --set values are applied last, so they override env vars on their paths
+        let mut applied = applied;
+        let env_count = applied.len();
+        applied.extend(args.set.iter().map(|(path, value)| EnvProperty {
+            env: format!("--set {}", path),
+            value: value.clone(),
+            path: path.clone(),
+        }));
+        let dropped = dropped_vars(&applied, &validated_config);
+        for props in dropped.iter().filter(|props| {
+            applied[..env_count]
+                .iter()
+                .any(|env_props| std::ptr::eq(env_props, **props))
+        }) {
+            eprintln!(
+                "unmapped: {} ('{}') did not end up in the config",
+                props.env, props.path
+            );
+        }
+    }
+
+    if args.check {
+        eprintln!("OK");
+        return Ok(());
+    }
+
+    if let Some(path) = &args.diff {
+        let existing = match serde_json::from_str(&std::fs::read_to_string(path)?)? {
+            Value::Object(existing) => existing,
+            _ => {
+                return Err(format!("The config {} must be a JSON object", path.display()).into());
+            }
+        };
+        let diff = diff_configs(&existing, &validated_config);
+        let changed: Map<String, Value> = diff
+            .changed
+            .iter()
+            .map(|(path, (old, new))| (path.clone(), serde_json::json!({"old": old, "new": new})))
+            .collect();
+        let report = serde_json::json!({
+            "added": diff.added,
+            "removed": diff.removed,
+            "changed": changed,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if !diff.is_empty() {
+            let count = diff.added.len() + diff.removed.len() + diff.changed.len();
+            return Err(ValidationFailed(format!(
+                "{} path(s) differ from {}",
+                count,
+                path.display()
+            ))
+            .into());
+        }
+        return Ok(());
+    }
+
+    if args.export_env {
+        let vars = config_to_env_with_options(&prefix, &validated_config, &env_options);
+        if args.command.is_empty() {
+            for (name, value) in &vars {
+                println!("export {}={}", name, shell_quote(value));
+            }
+            return Ok(());
+        }
+        return exec_with_env(&args.command, vars);
+    }
+
+    // Raw values are attached after validation so they never influence it
+    if args.include_raw {
+        let raw: Map<String, Value> = result
+            .values()
+            .map(|props| (props.path.clone(), Value::String(props.value.clone())))
+            .collect();
+        validated_config.insert(args.raw_key, Value::Object(raw));
+    }
+
+    // --output adds a file target alongside any --emit ones, in --format
+    let mut targets = args.emit;
+    if targets.is_empty() || args.output.is_some() {
+        targets.push(EmitTarget {
+            format: args.format,
+            path: args.output,
+        });
+    }
+
+    for target in targets {
+        let rendered = render(&validated_config, target.format, &prefix, &env_options)?;
+        match target.path {
+            Some(path) => std::fs::write(&path, format!("{}\n", rendered))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?,
+            None => println!("{}", rendered),
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,571 @@
+//! The schema-aware API. Env vars are mapped onto paths the same way as by the
+//! prefix-only functions in [`crate::v1`], after which each path segment takes
+//! the spelling of the schema property it matches, ignoring case. Segments the
+//! schema doesn't know about are kept as is, except below a map of scalar
+//! values (`additionalProperties`), where the rest of the name becomes one key,
+//! and where they spell a key matching one of the `patternProperties`.
+//!
+//! It also flattens schemas into typed properties with [`get_properties`] and
+//! parses env values to those types with [`parse_value`].
+
+use crate::{
+    DEFAULT_GROUPING_SEPARATORS, EnvOptions, EnvProperty, FixOptions, child_schema, coerce_scalar,
+    follow_ref, join_path, pattern_property, schema_type_name, split_list,
+};
+use jsonschema::primitive_type::PrimitiveType;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+
+/// Processes the environment variables that start with `prefix`, like
+/// [`crate::process_env_vars`], resolving their paths against `schema`.
+pub fn process_env_vars(
+    prefix: &str,
+    schema: &Value,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>> {
+    process_env_vars_from(prefix, schema, env::vars())
+}
+
+/// Same as `process_env_vars`, but reads the variables from `vars` instead of
+/// the process environment.
+pub fn process_env_vars_from<I>(
+    prefix: &str,
+    schema: &Value,
+    vars: I,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    process_env_vars_from_with_options(prefix, schema, vars, &EnvOptions::default())
+}
+
+/// Same as `process_env_vars_from`, but with explicit `EnvOptions`. With
+/// `options.match_snake_case`, runs of segments also match the property whose
+/// snake_case form they spell, so `PREFIX_MAX_CONNS` sets `maxConns`; it is an
+/// error if they spell more than one property.
+pub fn process_env_vars_from_with_options<I>(
+    prefix: &str,
+    schema: &Value,
+    vars: I,
+    options: &EnvOptions,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let result = crate::process_env_vars_from_with_options(prefix, vars, options)?;
+    resolve_paths(schema, result, options)
+}
+
+/// Processes the environment variables whose names match `pattern`, like
+/// [`crate::process_env_vars_matching_from`], resolving their paths against
+/// `schema`.
+pub fn process_env_vars_matching_from<I>(
+    pattern: &Regex,
+    schema: &Value,
+    vars: I,
+    options: &EnvOptions,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let result = crate::process_env_vars_matching_from(pattern, vars, options)?;
+    resolve_paths(schema, result, options)
+}
+
+/// Resolves the path of every property against `schema`.
+fn resolve_paths(
+    schema: &Value,
+    mut properties: BTreeMap<String, EnvProperty>,
+    options: &EnvOptions,
+) -> Result<BTreeMap<String, EnvProperty>, Box<dyn std::error::Error>> {
+    for property in properties.values_mut() {
+        property.path = resolve_path(schema, &property.path, options)
+            .map_err(|e| format!("Environment variable {} {}", property.env, e))?;
+    }
+    Ok(properties)
+}
+
+/// Rewrites each segment of a dotted `path` to the schema property it matches
+/// case-insensitively, walking down the schema as it goes. A segment that
+/// reaches a wildcard map (an object whose `additionalProperties` describe
+/// scalar values, e.g. `app.ports.*`) takes the rest of the path, joined back
+/// with underscores, as its key, so `APP_PORTS_ADMIN_HTTP` lands at
+/// `app.ports.admin_http`.
+fn resolve_path(root: &Value, path: &str, options: &EnvOptions) -> Result<String, String> {
+    let segments: Vec<&str> = path.split('.').collect();
+    let mut current = Some(follow_ref(root, root));
+    let mut resolved = Vec::with_capacity(segments.len());
+    let mut index = 0;
+    while index < segments.len() {
+        let segment = segments[index];
+        let direct = current.and_then(|schema| matching_property(schema, segment));
+        let snake_case_match = match (&direct, current) {
+            (None, Some(schema)) if options.match_snake_case => {
+                snake_case_property(schema, &segments[index..])?
+            }
+            _ => None,
+        };
+        let (key, consumed) = match (direct, snake_case_match) {
+            (Some(key), _) => (key, 1),
+            (None, Some(found)) => found,
+            (None, None)
+                if let Some(found) = current
+                    .and_then(|schema| pattern_property_key(root, schema, &segments[index..])) =>
+            {
+                found
+            }
+            (None, None) if current.is_some_and(|schema| is_scalar_map(root, schema)) => {
+                resolved.push(segments[index..].join("_"));
+                break;
+            }
+            (None, None) => (segment.to_string(), 1),
+        };
+        current = current.and_then(|schema| child_schema(root, schema, &key));
+        resolved.push(key);
+        index += consumed;
+    }
+    Ok(resolved.join("."))
+}
+
+/// Finds the property of `schema` whose snake_case form is spelled by the
+/// leading `segments` joined with underscores, returning it with the number of
+/// segments used. Shorter runs are tried first.
+fn snake_case_property(
+    schema: &Value,
+    segments: &[&str],
+) -> Result<Option<(String, usize)>, String> {
+    let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+        return Ok(None);
+    };
+    for length in 2..=segments.len() {
+        let candidate = segments[..length].join("_").to_lowercase();
+        let matches: Vec<&String> = properties
+            .keys()
+            .filter(|key| snake_case(key) == candidate)
+            .collect();
+        match matches.as_slice() {
+            [] => continue,
+            [key] => return Ok(Some(((*key).clone(), length))),
+            keys => {
+                return Err(format!(
+                    "is ambiguous: '{}' matches the schema keys {}",
+                    candidate,
+                    keys.iter()
+                        .map(|key| format!("'{}'", key))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Converts a camelCase or PascalCase key to snake_case, e.g. `maxConns` to
+/// `max_conns` and `APIKey` to `api_key`. Existing underscores are kept.
+fn snake_case(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    let mut snake = String::with_capacity(key.len() + 4);
+    for (index, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && index > 0 {
+            let previous = chars[index - 1];
+            let next_is_lower = chars.get(index + 1).is_some_and(|n| n.is_lowercase());
+            let boundary = previous.is_lowercase()
+                || previous.is_ascii_digit()
+                || (previous.is_uppercase() && next_is_lower);
+            if boundary {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+/// Joins the leading `segments` with underscores into the shortest key that
+/// matches one of the `patternProperties` of `schema`, returning it with the
+/// number of segments used. A pattern describing a scalar takes all of the
+/// segments, like a map of scalars does, so `SERVICE_AUTH_PORT` becomes the
+/// key `service_auth_port` under a `^service_` pattern.
+fn pattern_property_key(
+    root: &Value,
+    schema: &Value,
+    segments: &[&str],
+) -> Option<(String, usize)> {
+    schema.get("patternProperties")?;
+    (1..=segments.len()).find_map(|length| {
+        let key = segments[..length].join("_");
+        let subschema = pattern_property(schema, &key)?;
+        if is_scalar(follow_ref(root, subschema)) {
+            Some((segments.join("_"), segments.len()))
+        } else {
+            Some((key, length))
+        }
+    })
+}
+
+/// Finds the property of `schema` named `segment`, preferring an exact match
+/// over one that ignores case.
+fn matching_property(schema: &Value, segment: &str) -> Option<String> {
+    let properties = schema.get("properties").and_then(Value::as_object)?;
+    properties
+        .keys()
+        .find(|key| key.as_str() == segment)
+        .or_else(|| {
+            properties
+                .keys()
+                .find(|key| key.eq_ignore_ascii_case(segment))
+        })
+        .cloned()
+}
+
+/// Whether `schema` maps arbitrary keys to scalar values through its
+/// `additionalProperties`.
+fn is_scalar_map(root: &Value, schema: &Value) -> bool {
+    schema
+        .get("additionalProperties")
+        .filter(|additional| additional.is_object())
+        .map(|additional| follow_ref(root, additional))
+        .is_some_and(is_scalar)
+}
+
+/// Whether `schema` describes a scalar rather than an object or array.
+fn is_scalar(schema: &Value) -> bool {
+    ![
+        "properties",
+        "patternProperties",
+        "additionalProperties",
+        "items",
+        "prefixItems",
+    ]
+    .iter()
+    .any(|keyword| schema.get(keyword).is_some())
+}
+
+/// A schema property flattened to its dotted path and declared type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertyInfo {
+    pub path: String,
+    /// The declared type, e.g. `integer`, `array[string]` for arrays, or
+    /// `integer?` for `["integer", "null"]`.
+    pub property_type: String,
+    /// Whether the schema sets `"x-strict-string": true`, asking for values
+    /// to be parsed with `ParseOptions::strict_strings`.
+    pub strict_string: bool,
+}
+
+/// Options for `parse_value_with_options`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ParseOptions {
+    /// Refuse `string` values that parse as a finite number, which usually
+    /// means the wrong variable was set. Off by default since zip codes, IDs and the
+    /// like are strings made of digits.
+    pub strict_strings: bool,
+}
+
+/// An env value parsed to the type of its property.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    String(String),
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+    StringArray(Vec<String>),
+    IntegerArray(Vec<i64>),
+    NumberArray(Vec<f64>),
+    BooleanArray(Vec<bool>),
+    ObjectArray(Vec<Map<String, Value>>),
+    Null,
+}
+
+impl PropertyValue {
+    pub fn is_null(&self) -> bool {
+        matches!(self, PropertyValue::Null)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PropertyValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            PropertyValue::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a `Number`, or of an `Integer` as a float.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            PropertyValue::Number(value) => Some(*value),
+            PropertyValue::Integer(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            PropertyValue::Boolean(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str_array(&self) -> Option<&[String]> {
+        match self {
+            PropertyValue::StringArray(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64_array(&self) -> Option<&[i64]> {
+        match self {
+            PropertyValue::IntegerArray(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64_array(&self) -> Option<&[f64]> {
+        match self {
+            PropertyValue::NumberArray(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool_array(&self) -> Option<&[bool]> {
+        match self {
+            PropertyValue::BooleanArray(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_object_array(&self) -> Option<&[Map<String, Value>]> {
+        match self {
+            PropertyValue::ObjectArray(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Converts the value into the JSON value it stands for. Numbers JSON
+    /// cannot represent (NaN and infinities) become null.
+    pub fn into_json(self) -> Value {
+        match self {
+            PropertyValue::String(value) => Value::String(value),
+            PropertyValue::Integer(value) => Value::from(value),
+            PropertyValue::Number(value) => Value::from(value),
+            PropertyValue::Boolean(value) => Value::Bool(value),
+            PropertyValue::StringArray(values) => Value::from(values),
+            PropertyValue::IntegerArray(values) => Value::from(values),
+            PropertyValue::NumberArray(values) => Value::from(values),
+            PropertyValue::BooleanArray(values) => Value::from(values),
+            PropertyValue::ObjectArray(values) => {
+                Value::Array(values.into_iter().map(Value::Object).collect())
+            }
+            PropertyValue::Null => Value::Null,
+        }
+    }
+}
+
+impl From<PropertyValue> for Value {
+    fn from(value: PropertyValue) -> Self {
+        value.into_json()
+    }
+}
+
+/// Flattens the `properties` of `schema` (following `$ref`s within `root`)
+/// into one `PropertyInfo` per leaf, with paths starting at `prefix`. Arrays
+/// are typed by their items as `array[<type>]`, types that also allow `null`
+/// (e.g. `["integer", "null"]`) get a trailing `?` as `parse_value` expects,
+/// and maps (`additionalProperties`) are listed under a `*` key such as
+/// `app.ports.*`.
+pub fn get_properties(schema: &Value, root: &Value, prefix: &str) -> Vec<PropertyInfo> {
+    let schema = follow_ref(root, schema);
+    let properties = schema.get("properties").and_then(Value::as_object);
+    let additional = schema
+        .get("additionalProperties")
+        .filter(|additional| additional.is_object());
+
+    if properties.is_none() && additional.is_none() {
+        if prefix.is_empty() {
+            return Vec::new();
+        }
+        let property_type = match schema.get("items") {
+            Some(items) if items.is_object() => {
+                let items = follow_ref(root, items);
+                let item_type = match items.get("properties") {
+                    Some(_) => "object".to_string(),
+                    None => schema_type_name(items),
+                };
+                format!(
+                    "array[{}]{}",
+                    item_type,
+                    if nullable_type(schema).is_some() {
+                        "?"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            _ => match nullable_type(schema) {
+                Some(base_type) => format!("{}?", base_type),
+                None => schema_type_name(schema),
+            },
+        };
+        return vec![PropertyInfo {
+            path: prefix.to_string(),
+            property_type,
+            strict_string: schema
+                .get("x-strict-string")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        }];
+    }
+
+    let mut out = Vec::new();
+    for (key, property) in properties.into_iter().flatten() {
+        out.extend(get_properties(property, root, &join_path(prefix, key)));
+    }
+    if let Some(additional) = additional {
+        out.extend(get_properties(additional, root, &join_path(prefix, "*")));
+    }
+    out
+}
+
+/// Returns `T` for a schema whose `type` is `[T, "null"]` (in either order).
+fn nullable_type(schema: &Value) -> Option<&str> {
+    let types = schema.get("type")?.as_array()?;
+    match types
+        .iter()
+        .filter_map(Value::as_str)
+        .collect::<Vec<&str>>()[..]
+    {
+        [base_type, "null"] | ["null", base_type] if types.len() == 2 => Some(base_type),
+        _ => None,
+    }
+}
+
+/// Parses an env `value` as `property_type`, one of the types returned by
+/// `get_properties`. Array items are split on spaces and commas, except for
+/// `array[object]`, whose value must be a JSON array of objects. Any value is
+/// accepted as a `string`, and `null` accepts `null` or an empty value. A
+/// trailing `?` makes a type nullable: `integer?` parses an empty (or
+/// whitespace-only) value as `Null` and anything else as an `integer`. Errors
+/// name the `path` of the property.
+pub fn parse_value(value: &str, property_type: &str, path: &str) -> Result<PropertyValue, String> {
+    parse_value_with_options(value, property_type, path, &ParseOptions::default())
+}
+
+/// Same as `parse_value`, but configured by `options`.
+pub fn parse_value_with_options(
+    value: &str,
+    property_type: &str,
+    path: &str,
+    options: &ParseOptions,
+) -> Result<PropertyValue, String> {
+    let unsupported = || {
+        format!(
+            "Unsupported property type '{}' at '{}'",
+            property_type, path
+        )
+    };
+
+    if let Some(base_type) = property_type.strip_suffix('?') {
+        if value.trim().is_empty() {
+            return Ok(PropertyValue::Null);
+        }
+        return parse_value_with_options(value, base_type, path, options);
+    }
+
+    if let Some(item_type) = property_type
+        .strip_prefix("array[")
+        .and_then(|t| t.strip_suffix(']'))
+    {
+        if item_type == "object" {
+            return parse_object_array(value, path).map(PropertyValue::ObjectArray);
+        }
+        let item_type = PrimitiveType::try_from(item_type).map_err(|_| unsupported())?;
+        let items = split_list(None, value, &FixOptions::default());
+        let parsed = items
+            .iter()
+            .map(|item| parse_scalar(item, item_type, path))
+            .collect::<Result<Vec<Value>, String>>()?;
+        return match item_type {
+            PrimitiveType::String => Ok(PropertyValue::StringArray(
+                items.iter().map(|item| item.to_string()).collect(),
+            )),
+            PrimitiveType::Integer => Ok(PropertyValue::IntegerArray(
+                parsed.iter().filter_map(Value::as_i64).collect(),
+            )),
+            PrimitiveType::Number => Ok(PropertyValue::NumberArray(
+                parsed.iter().filter_map(Value::as_f64).collect(),
+            )),
+            PrimitiveType::Boolean => Ok(PropertyValue::BooleanArray(
+                parsed.iter().filter_map(Value::as_bool).collect(),
+            )),
+            _ => Err(unsupported()),
+        };
+    }
+
+    let primitive_type = PrimitiveType::try_from(property_type).map_err(|_| unsupported())?;
+    let parsed = parse_scalar(value, primitive_type, path)?;
+    match (primitive_type, parsed) {
+        // Words like `nan` and `inf` parse as floats but aren't JSON numbers
+        (PrimitiveType::String, _)
+            if options.strict_strings && value.parse::<f64>().is_ok_and(f64::is_finite) =>
+        {
+            Err(format!(
+                "Expected a string at '{}' but got the number '{}'",
+                path, value
+            ))
+        }
+        (PrimitiveType::String, _) => Ok(PropertyValue::String(value.to_string())),
+        (PrimitiveType::Integer, Value::Number(n)) => n
+            .as_i64()
+            .map(PropertyValue::Integer)
+            .ok_or_else(unsupported),
+        (PrimitiveType::Number, Value::Number(n)) => n
+            .as_f64()
+            .map(PropertyValue::Number)
+            .ok_or_else(unsupported),
+        (PrimitiveType::Boolean, Value::Bool(b)) => Ok(PropertyValue::Boolean(b)),
+        (PrimitiveType::Null, Value::Null) => Ok(PropertyValue::Null),
+        _ => Err(unsupported()),
+    }
+}
+
+/// Parses `value` as a JSON array whose elements are all objects.
+fn parse_object_array(value: &str, path: &str) -> Result<Vec<Map<String, Value>>, String> {
+    let parsed: Value = serde_json::from_str(value)
+        .map_err(|e| format!("Invalid JSON array of objects at '{}': {}", path, e))?;
+    let Value::Array(items) = parsed else {
+        return Err(format!(
+            "Expected a JSON array of objects at '{}' but got {}",
+            path, parsed
+        ));
+    };
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| match item {
+            Value::Object(object) => Ok(object),
+            other => Err(format!(
+                "Expected an object at '{}.{}' but got {}",
+                path, index, other
+            )),
+        })
+        .collect()
+}
+
+/// Coerces a scalar `value` like the schema-driven fixes do, naming `path` in
+/// the error.
+fn parse_scalar(value: &str, primitive_type: PrimitiveType, path: &str) -> Result<Value, String> {
+    coerce_scalar(value, primitive_type, DEFAULT_GROUPING_SEPARATORS).map_err(|reason| {
+        format!(
+            "Invalid {} '{}' at '{}': {}",
+            primitive_type, value, path, reason
+        )
+    })
+}
@@ -1,34 +1,68 @@
 use env_to_schema_json::{
-    create_nested_json, fix_and_validate_json, process_env_vars, resolve_ref,
+    ArraySplit, DanglingRef, EnvOptions, FixError, FixOptions, ParseOptions, PropertyValue,
+    apply_defaults, apply_presence_flags, apply_type_hints, build_config_from, config_diff,
+    config_to_env_with_options, create_nested_json, create_nested_json_for_schema,
+    create_nested_json_with_sep, dangling_refs, expected_env_vars, expected_env_vars_with_options,
+    fix_and_validate_json, fix_and_validate_json_with_options, get_properties,
+    instance_path_to_string, mapping_markdown, mapping_markdown_with_options, parse_dotenv,
+    parse_value, parse_value_with_options, path_to_env_var_with_options, process_env_vars_from,
+    process_env_vars_from_with_options, process_env_vars_iter_from, process_env_vars_matching_from,
+    quote_env_value, remove_path, resolve_ref, resolve_ref_chain, take_unset_paths, v1, v2,
 };
+use jsonschema::Draft;
+use regex::Regex;
 use serde_json::{Map, Value, json};
-use std::env;
+use std::collections::HashMap;
 
 #[test]
 fn test_process_env_vars() {
-    unsafe {
-        env::set_var("TEST_FOO_BAR", "value1");
-        env::set_var("TEST_BAZ__QUX", "value2");
+    let vars = [("TEST_FOO_BAR", "value1"), ("TEST_BAZ__QUX", "value2")]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
 
-        let result = process_env_vars("TEST_").unwrap();
+    let result = process_env_vars_from("TEST_", vars).unwrap();
 
-        assert_eq!(result.len(), 2);
-        assert_eq!(result["TEST_FOO_BAR"].path, "foo.bar");
-        assert_eq!(result["TEST_FOO_BAR"].value, "value1");
-        assert_eq!(result["TEST_BAZ__QUX"].path, "baz_qux");
-        assert_eq!(result["TEST_BAZ__QUX"].value, "value2");
+    assert_eq!(result.len(), 2);
+    assert_eq!(result["TEST_FOO_BAR"].path, "foo.bar");
+    assert_eq!(result["TEST_FOO_BAR"].value, "value1");
+    assert_eq!(result["TEST_BAZ__QUX"].path, "baz_qux");
+    assert_eq!(result["TEST_BAZ__QUX"].value, "value2");
+}
 
-        env::remove_var("TEST_FOO_BAR");
-        env::remove_var("TEST_BAZ__QUX");
-    }
+#[test]
+fn test_process_env_vars_max_depth() {
+    let key = format!("DEPTH_{}", vec!["A"; 40].join("_"));
+
+    let vars = vec![(key.clone(), "value".to_string())];
+    let result = process_env_vars_from_with_options("DEPTH_", vars, &EnvOptions::default());
+
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains(&key));
+    assert!(error.contains("40 levels deep, exceeding the maximum of 32"));
+}
+
+#[test]
+fn test_process_env_vars_key_map() {
+    let options = EnvOptions {
+        key_map: HashMap::from([("OAUTH2".to_string(), "oAuth2".to_string())]),
+        ..EnvOptions::default()
+    };
+    let vars = vec![
+        ("KEYMAP_OAUTH2_CLIENT__ID".to_string(), "abc".to_string()),
+        ("KEYMAP_OTHER_OAUTH2".to_string(), "def".to_string()),
+    ];
+
+    let result = process_env_vars_from_with_options("KEYMAP_", vars, &options).unwrap();
+
+    assert_eq!(result["KEYMAP_OAUTH2_CLIENT__ID"].path, "oAuth2.client_id");
+    assert_eq!(result["KEYMAP_OTHER_OAUTH2"].path, "other.oAuth2");
 }
 
 #[test]
 fn test_create_nested_json() {
     let mut config = Map::new();
 
-    create_nested_json(&mut config, "a.b.0.c", "value1");
-    create_nested_json(&mut config, "a.b.1", "value2");
+    create_nested_json(&mut config, "a.b.0.c", "value1").unwrap();
+    create_nested_json(&mut config, "a.b.1", "value2").unwrap();
 
     let expected = json!({
         "a": {
@@ -68,7 +102,7 @@ fn test_fix_and_validate_json() {
     );
     config.insert("array".to_string(), Value::String("1, 2, 3".to_string()));
 
-    let result = fix_and_validate_json(&schema, config, false).unwrap();
+    let result = fix_and_validate_json(&schema, config).unwrap();
 
     assert_eq!(result["string"], json!("string"));
     assert_eq!(result["number"], json!(42));
@@ -78,26 +112,2358 @@ fn test_fix_and_validate_json() {
 }
 
 #[test]
-fn test_resolve_ref() {
+fn test_fix_and_validate_json_single_element_type_array() {
     let schema = json!({
-        "definitions": {
-            "address": {
+        "type": "object",
+        "properties": {
+            "number": {"type": ["number"]},
+            "integer": {"type": ["integer"]}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("number".to_string(), Value::String("4.5".to_string()));
+    config.insert("integer".to_string(), Value::String("42".to_string()));
+
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["number"], json!(4.5));
+    assert_eq!(result["integer"], json!(42));
+}
+
+#[test]
+fn test_fix_and_validate_json_signed_numbers() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "offsets": {"type": "array", "items": {"type": "integer"}},
+            "spaced": {"type": "array", "items": {"type": "integer"}},
+            "positive": {"type": "integer"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("offsets".to_string(), Value::String("-1,-2,-3".to_string()));
+    config.insert("spaced".to_string(), Value::String("-1 -2 -3".to_string()));
+    config.insert("positive".to_string(), Value::String("+5".to_string()));
+
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["offsets"], json!([-1, -2, -3]));
+    assert_eq!(result["spaced"], json!([-1, -2, -3]));
+    assert_eq!(result["positive"], json!(5));
+}
+
+#[test]
+fn test_fix_and_validate_json_array_length_errors() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "ports": {
+                "type": "array",
+                "items": {"type": "integer"},
+                "minItems": 2,
+                "maxItems": 3
+            }
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("ports".to_string(), Value::String("80".to_string()));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Array at 'ports' must contain at least 2 items but has 1"
+    );
+
+    let mut config = Map::new();
+    config.insert(
+        "ports".to_string(),
+        Value::String("80,443,8080,8443".to_string()),
+    );
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Array at 'ports' must contain at most 3 items but has 4"
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_additional_properties_map() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "labels": {
+                "type": "object",
+                "additionalProperties": {"type": "integer"}
+            },
+            "groups": {
+                "type": "object",
+                "additionalProperties": {"type": "array", "items": {"type": "integer"}}
+            }
+        }
+    });
+
+    let vars = [
+        ("MAP_LABELS_REPLICAS", "3"),
+        ("MAP_LABELS_TEAM__SIZE", "12"),
+        ("MAP_LABELS_Priority", "1"),
+        ("MAP_GROUPS_ADMINS", "1,2"),
+    ]
+    .map(|(key, value)| (key.to_string(), value.to_string()));
+    let result = process_env_vars_from("MAP_", vars).unwrap();
+
+    let mut config = Map::new();
+    for props in result.values() {
+        create_nested_json(&mut config, &props.path, &props.value).unwrap();
+    }
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(
+        result["labels"],
+        json!({"replicas": 3, "team_size": 12, "priority": 1})
+    );
+    assert_eq!(result["groups"], json!({"admins": [1, 2]}));
+}
+
+#[test]
+fn test_fix_and_validate_json_unique_items_dedupes() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "tags": {"type": "array", "items": {"type": "string"}, "uniqueItems": true},
+            "ports": {"type": "array", "items": {"type": "integer"}, "uniqueItems": true},
+            "list": {"type": "array", "items": {"type": "string"}}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("tags".to_string(), Value::String("a,b,a".to_string()));
+    config.insert("ports".to_string(), Value::String("80,443,80".to_string()));
+    config.insert("list".to_string(), Value::String("a,b,a".to_string()));
+
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["tags"], json!(["a", "b"]));
+    assert_eq!(result["ports"], json!([80, 443]));
+    assert_eq!(result["list"], json!(["a", "b", "a"]));
+}
+
+#[test]
+fn test_config_diff() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "database": {
                 "type": "object",
                 "properties": {
-                    "street": {"type": "string"}
+                    "host": {"type": "string"},
+                    "port": {"type": "integer"}
                 }
             }
         }
     });
+    let env_a = vec![
+        ("DIFF_DATABASE_HOST".to_string(), "db".to_string()),
+        ("DIFF_DATABASE_PORT".to_string(), "5432".to_string()),
+    ];
+    let env_b = vec![
+        ("DIFF_DATABASE_HOST".to_string(), "db".to_string()),
+        ("DIFF_DATABASE_PORT".to_string(), "6543".to_string()),
+    ];
 
-    let result = resolve_ref(&schema, "#/definitions/address").unwrap();
-    let expected = json!({
+    let diff = config_diff("DIFF_", &schema, env_a, env_b).unwrap();
+
+    assert!(diff.added.is_empty());
+    assert!(diff.removed.is_empty());
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed["database.port"], (json!(5432), json!(6543)));
+}
+
+#[test]
+fn test_apply_presence_flags() {
+    let schema = json!({
         "type": "object",
         "properties": {
-            "street": {"type": "string"}
+            "features": {
+                "type": "object",
+                "properties": {
+                    "verbose": {"type": "boolean", "x-env-presence-bool": true},
+                    "metrics": {"type": "boolean", "x-env-presence-bool": true}
+                }
+            }
         }
     });
+    let vars = vec![("FLAG_FEATURES_VERBOSE".to_string(), String::new())];
 
-    assert_eq!(result, &expected);
-    assert!(resolve_ref(&schema, "#/invalid/path").is_none());
+    let properties = process_env_vars_from("FLAG_", vars).unwrap();
+    let mut config = Map::new();
+    for props in properties.values() {
+        create_nested_json(&mut config, &props.path, &props.value).unwrap();
+    }
+    apply_presence_flags(&schema, &properties, &mut config);
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["features"]["verbose"], json!(true));
+    assert_eq!(result["features"]["metrics"], json!(false));
+}
+
+#[test]
+fn test_fix_and_validate_json_units() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "cache_size": {"type": "integer", "x-env-unit": "bytes"},
+            "timeout": {"type": "integer", "x-env-unit": "seconds"},
+            "ratio": {"type": "number", "x-env-unit": "percent"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("cache_size".to_string(), Value::String("2MiB".to_string()));
+    config.insert("timeout".to_string(), Value::String("1h30m".to_string()));
+    config.insert("ratio".to_string(), Value::String("75%".to_string()));
+
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["cache_size"], json!(2 * 1024 * 1024));
+    assert_eq!(result["timeout"], json!(5400));
+    assert_eq!(result["ratio"], json!(0.75));
+}
+
+#[test]
+fn test_fix_and_validate_json_unit_overflow() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "cache_size": {"type": "integer", "x-env-unit": "bytes"},
+            "timeout": {"type": "integer", "x-env-unit": "seconds"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("timeout".to_string(), json!("999999999999999d"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("Invalid duration: 999999999999999d"),
+        "{}",
+        error
+    );
+
+    let mut config = Map::new();
+    config.insert("cache_size".to_string(), json!("99999999999TiB"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("Invalid byte size: 99999999999TiB"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_unknown_unit() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "distance": {"type": "integer", "x-env-unit": "furlongs"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("distance".to_string(), Value::String("3".to_string()));
+
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+
+    assert_eq!(
+        error,
+        "Cannot coerce '3' at 'distance' to furlongs: Unknown unit 'furlongs' for 'distance'"
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_array_of_objects() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "servers": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "host": {"type": "string"},
+                        "port": {"type": "integer"}
+                    }
+                }
+            },
+            "ports": {"type": "array", "items": {"type": "integer"}}
+        }
+    });
+    let vars = vec![
+        ("AOO_SERVERS_0_HOST".to_string(), "a.example".to_string()),
+        ("AOO_SERVERS_0_PORT".to_string(), "8080".to_string()),
+        ("AOO_SERVERS_1_HOST".to_string(), "b.example".to_string()),
+        ("AOO_SERVERS_1_PORT".to_string(), "9090".to_string()),
+        ("AOO_PORTS_0".to_string(), "80".to_string()),
+        ("AOO_PORTS_1".to_string(), "443".to_string()),
+    ];
+
+    let mut config = Map::new();
+    let mut properties: Vec<_> = process_env_vars_from("AOO_", vars)
+        .unwrap()
+        .into_values()
+        .collect();
+    properties.sort_by(|a, b| a.path.cmp(&b.path));
+    for props in properties {
+        create_nested_json(&mut config, &props.path, &props.value).unwrap();
+    }
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(
+        result["servers"],
+        json!([
+            {"host": "a.example", "port": 8080},
+            {"host": "b.example", "port": 9090}
+        ])
+    );
+    assert_eq!(result["ports"], json!([80, 443]));
+}
+
+#[test]
+fn test_fix_and_validate_json_boolean_spellings() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "flag": {"type": "boolean"}
+        }
+    });
+
+    let spellings = [
+        ("true", true),
+        ("false", false),
+        ("yes", true),
+        ("no", false),
+        ("on", true),
+        ("off", false),
+        ("1", true),
+        ("0", false),
+        ("enabled", true),
+        ("disabled", false),
+        ("YES", true),
+        (" Off ", false),
+    ];
+    for (spelling, expected) in spellings {
+        let mut config = Map::new();
+        config.insert("flag".to_string(), Value::String(spelling.to_string()));
+
+        let result = fix_and_validate_json(&schema, config).unwrap();
+
+        assert_eq!(result["flag"], json!(expected), "spelling {:?}", spelling);
+    }
+
+    let mut config = Map::new();
+    config.insert("flag".to_string(), Value::String("maybe".to_string()));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Cannot coerce 'maybe' at 'flag' to boolean: Unsupported type: Boolean"
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_nullable() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "nickname": {"type": ["string", "null"]},
+            "middle_name": {"type": ["string", "null"]},
+            "retries": {"type": ["integer", "null"]},
+            "nothing": {"type": "null"},
+            "name": {"type": "string"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("nickname".to_string(), Value::String("NULL".to_string()));
+    config.insert("middle_name".to_string(), Value::String(String::new()));
+    config.insert("retries".to_string(), Value::String(String::new()));
+    config.insert("nothing".to_string(), Value::String("null".to_string()));
+    config.insert("name".to_string(), Value::String("null".to_string()));
+
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["nickname"], Value::Null);
+    assert_eq!(result["middle_name"], json!(""));
+    assert_eq!(result["retries"], Value::Null);
+    assert_eq!(result["nothing"], Value::Null);
+    assert_eq!(result["name"], json!("null"));
+
+    let mut config = Map::new();
+    config.insert("nothing".to_string(), Value::String("nope".to_string()));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Cannot coerce 'nope' at 'nothing' to null: Unsupported type: Null"
+    );
+}
+
+#[test]
+fn test_apply_type_hints() {
+    let mut config = Map::new();
+    create_nested_json(&mut config, "app.workers", "int:4").unwrap();
+    create_nested_json(&mut config, "app.ratio", "float:0.5").unwrap();
+    create_nested_json(&mut config, "app.debug", "bool:true").unwrap();
+    create_nested_json(&mut config, "app.version", "str:42").unwrap();
+    create_nested_json(&mut config, "app.limits", r#"json:{"cpu":2}"#).unwrap();
+    create_nested_json(&mut config, "app.url", "http://localhost").unwrap();
+    create_nested_json(&mut config, "app.name", "plain").unwrap();
+
+    apply_type_hints(&mut config).unwrap();
+
+    assert_eq!(
+        Value::Object(config),
+        json!({
+            "app": {
+                "workers": 4,
+                "ratio": 0.5,
+                "debug": true,
+                "version": "42",
+                "limits": {"cpu": 2},
+                "url": "http://localhost",
+                "name": "plain"
+            }
+        })
+    );
+
+    let mut config = Map::new();
+    create_nested_json(&mut config, "workers", "int:four").unwrap();
+    let error = apply_type_hints(&mut config).unwrap_err();
+    assert!(error.starts_with("Invalid type hint at 'workers'"));
+}
+
+#[test]
+fn test_dangling_refs() {
+    let schema = json!({
+        "definitions": {
+            "port": {"type": "integer"}
+        },
+        "properties": {
+            "http": {"$ref": "#/definitions/port"},
+            "https": {"$ref": "#/definitions/prot"},
+            "self": {"$ref": "#"}
+        }
+    });
+
+    let dangling = dangling_refs(&schema);
+
+    assert_eq!(
+        dangling,
+        vec![DanglingRef {
+            location: "#/properties/https".to_string(),
+            reference: "#/definitions/prot".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_array_delimiter() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "semicolon": {"type": "array", "x-delimiter": ";"},
+            "pipe": {"type": "array", "items": {"type": "integer"}, "x-delimiter": "|"},
+            "default": {"type": "array"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert(
+        "semicolon".to_string(),
+        Value::String("/a b; /c d".to_string()),
+    );
+    config.insert("pipe".to_string(), Value::String("1 | 2|3".to_string()));
+    config.insert(
+        "default".to_string(),
+        Value::String("/a b,/c d".to_string()),
+    );
+
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["semicolon"], json!(["/a b", "/c d"]));
+    assert_eq!(result["pipe"], json!([1, 2, 3]));
+    assert_eq!(result["default"], json!(["/a", "b", "/c", "d"]));
+}
+
+#[test]
+fn test_fix_and_validate_json_typed_arrays() {
+    let schema = json!({
+        "type": "object",
+        "$defs": {"port": {"type": "integer"}},
+        "properties": {
+            "ports": {"type": "array", "items": {"$ref": "#/$defs/port"}},
+            "weights": {"type": "array", "items": {"type": "number"}},
+            "flags": {"type": "array", "items": {"type": "boolean"}}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("ports".to_string(), json!("80,443"));
+    config.insert("weights".to_string(), json!("1.5 2"));
+    config.insert("flags".to_string(), json!("true,off,1"));
+
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["ports"], json!([80, 443]));
+    assert_eq!(result["weights"], json!([1.5, 2]));
+    assert_eq!(result["flags"], json!([true, false, true]));
+}
+
+#[test]
+fn test_fix_and_validate_json_const() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "port": {"const": 8080},
+            "debug": {"const": false}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("port".to_string(), json!("8080"));
+    config.insert("debug".to_string(), json!("false"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["port"], json!(8080));
+    assert_eq!(result["debug"], json!(false));
+
+    let mut config = Map::new();
+    config.insert("port".to_string(), json!("9090"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("'port' is fixed to 8080 and cannot be overridden"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_trims_scalars() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "port": {"type": "integer"},
+            "ratio": {"type": "number"},
+            "debug": {"type": "boolean"},
+            "motd": {"type": "string"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("port".to_string(), json!(" 8080 "));
+    config.insert("ratio".to_string(), json!("0.5\n"));
+    config.insert("debug".to_string(), json!("\ttrue"));
+    config.insert("motd".to_string(), json!("  hello   world "));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["port"], json!(8080));
+    assert_eq!(result["ratio"], json!(0.5));
+    assert_eq!(result["debug"], json!(true));
+    assert_eq!(result["motd"], json!("  hello   world "));
+}
+
+#[test]
+fn test_fix_and_validate_json_suggests_additional_properties() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {"port": {"type": "integer"}},
+                "additionalProperties": false
+            }
+        },
+        "additionalProperties": false
+    });
+
+    let mut config = Map::new();
+    config.insert("database".to_string(), json!({"prot": "5432"}));
+    config.insert("zzz".to_string(), json!("x"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+
+    assert!(
+        error.contains(
+            "Property 'database.prot' is not allowed by the schema; did you mean 'database.port'?"
+        ),
+        "{}",
+        error
+    );
+    assert!(
+        error.contains("Property 'zzz' is not allowed by the schema"),
+        "{}",
+        error
+    );
+    assert!(
+        !error.contains("'zzz' is not allowed by the schema;"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_keep_empty() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "tags": {"type": "array", "items": {"type": "string"}},
+            "slots": {"type": "array", "items": {"type": "string"}, "x-keep-empty": true},
+            "piped": {"type": "array", "x-delimiter": "|", "x-keep-empty": true}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("tags".to_string(), json!("a,,b,"));
+    config.insert("slots".to_string(), json!("a,, b c,"));
+    config.insert("piped".to_string(), json!("|x| "));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+
+    assert_eq!(result["tags"], json!(["a", "b"]));
+    assert_eq!(result["slots"], json!(["a", "", "b", "c", ""]));
+    assert_eq!(result["piped"], json!(["", "x", ""]));
+}
+
+#[test]
+fn test_fix_and_validate_json_bool_as_int() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "legacy": {"type": "integer", "x-bool-as-int": true},
+            "count": {"type": "integer"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("legacy".to_string(), json!("true"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["legacy"], json!(1));
+
+    let mut config = Map::new();
+    config.insert("legacy".to_string(), json!("off"));
+    config.insert("count".to_string(), json!("7"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["legacy"], json!(0));
+    assert_eq!(result["count"], json!(7));
+
+    let mut config = Map::new();
+    config.insert("count".to_string(), json!("true"));
+    assert!(fix_and_validate_json(&schema, config).is_err());
+}
+
+#[test]
+fn test_instance_path_to_string() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "servers": {
+                "type": "array",
+                "items": {"type": "object", "properties": {"port": {"type": "integer"}}}
+            }
+        }
+    });
+    let instance = json!({"servers": [{"port": 1}, {"port": "x"}]});
+    let compiled = jsonschema::JSONSchema::compile(&schema).unwrap();
+    let paths: Vec<String> = compiled
+        .validate(&instance)
+        .unwrap_err()
+        .map(|error| instance_path_to_string(&error.instance_path))
+        .collect();
+    assert_eq!(paths, vec!["servers.1.port"]);
+
+    let errors: Vec<String> = compiled
+        .validate(&json!([]))
+        .unwrap_err()
+        .map(|error| instance_path_to_string(&error.instance_path))
+        .collect();
+    assert_eq!(errors, vec![""]);
+}
+
+#[test]
+fn test_fix_and_validate_json_nested_key_value_pairs() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "db": {
+                "type": "object",
+                "x-delimiter": ";",
+                "properties": {
+                    "host": {"type": "string"},
+                    "port": {"type": "integer"},
+                    "opts": {
+                        "type": "object",
+                        "properties": {"ssl": {"type": "boolean"}}
+                    }
+                }
+            }
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert(
+        "db".to_string(),
+        json!("host=localhost;port=5432;opts.ssl=true"),
+    );
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(
+        result["db"],
+        json!({"host": "localhost", "port": 5432, "opts": {"ssl": true}})
+    );
+
+    let mut config = Map::new();
+    config.insert("db".to_string(), json!("opts=x;opts.ssl=true"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Conflicting paths"), "{}", error);
+}
+
+#[test]
+fn test_fix_and_validate_json_newline_delimited_arrays() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "hosts": {"type": "array", "items": {"type": "string"}},
+            "ports": {"type": "array", "items": {"type": "integer"}}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("hosts".to_string(), json!("a.example\r\nb.example\r\n\r\n"));
+    config.insert("ports".to_string(), json!("80\n443\n"));
+    let result = fix_and_validate_json(&schema, config.clone()).unwrap();
+    assert_eq!(result["hosts"], json!(["a.example", "b.example"]));
+    assert_eq!(result["ports"], json!([80, 443]));
+
+    let options = FixOptions {
+        array_split: ArraySplit::Comma,
+        ..FixOptions::default()
+    };
+    let mut config = Map::new();
+    config.insert("hosts".to_string(), json!("a b,c\r\nd"));
+    let result = fix_and_validate_json_with_options(&schema, config, &options).unwrap();
+    assert_eq!(result["hosts"], json!(["a b", "c", "d"]));
+}
+
+#[test]
+fn test_fix_and_validate_json_x_format() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "timeout": {"type": "integer", "x-format": "duration-seconds"},
+            "cache": {"type": "integer", "x-format": "byte-size"},
+            "retries": {"type": "integer", "x-format": "not-a-format"}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("timeout".to_string(), json!("5m"));
+    config.insert("cache".to_string(), json!("2KiB"));
+    config.insert("retries".to_string(), json!("3"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["timeout"], json!(300));
+    assert_eq!(result["cache"], json!(2048));
+    assert_eq!(result["retries"], json!(3));
+
+    let mut config = Map::new();
+    config.insert("timeout".to_string(), json!("1h30m"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["timeout"], json!(5400));
+
+    let mut config = Map::new();
+    config.insert("timeout".to_string(), json!("5 minutes"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Invalid duration: 5 minutes"), "{}", error);
+}
+
+#[test]
+fn test_fix_and_validate_json_repeated_values() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "a": {"type": "integer"},
+            "b": {"type": "integer"},
+            "enabled": {"type": "boolean"},
+            "ports": {"type": "array", "items": {"type": "integer"}}
+        }
+    });
+    let mut config = Map::new();
+    config.insert("a".to_string(), json!("8080"));
+    config.insert("b".to_string(), json!("8080"));
+    config.insert("enabled".to_string(), json!("1"));
+    config.insert("ports".to_string(), json!("8080,1"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["a"], json!(8080));
+    assert_eq!(result["b"], json!(8080));
+    // The same value coerced to another type is not mixed up with the first
+    assert_eq!(result["enabled"], json!(true));
+    assert_eq!(result["ports"], json!([8080, 1]));
+
+    // A repeated value that fails to coerce fails the same way each time
+    let mut config = Map::new();
+    config.insert("a".to_string(), json!("abc"));
+    config.insert("b".to_string(), json!("abc"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Cannot coerce 'abc' at 'a' to integer: Unsupported type: Integer"
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_literal_values() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "servers": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {"host": {"type": "string"}}
+                }
+            },
+            "tags": {"type": "array", "items": {"type": "string"}},
+            "labels": {"type": "object"}
+        }
+    });
+    let mut config = Map::new();
+    config.insert(
+        "servers".to_string(),
+        json!(r#"[{"host":"a"},{"host":"b"}]"#),
+    );
+    config.insert("tags".to_string(), json!("a,b,c"));
+    config.insert("labels".to_string(), json!(r#"{"team": "core"}"#));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["servers"], json!([{"host": "a"}, {"host": "b"}]));
+    assert_eq!(result["tags"], json!(["a", "b", "c"]));
+    assert_eq!(result["labels"], json!({"team": "core"}));
+
+    let mut config = Map::new();
+    config.insert("servers".to_string(), json!(r#"[{"host":"a"},"#));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Invalid JSON"), "{}", error);
+}
+
+#[test]
+fn test_fix_and_validate_json_union_types() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "limit": {"type": ["integer", "string"]},
+            "flag": {"type": ["integer", "boolean"]}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("limit".to_string(), json!("42"));
+    config.insert("flag".to_string(), json!("yes"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["limit"], json!(42));
+    assert_eq!(result["flag"], json!(true));
+
+    let mut config = Map::new();
+    config.insert("limit".to_string(), json!("unlimited"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["limit"], json!("unlimited"));
+
+    let mut config = Map::new();
+    config.insert("flag".to_string(), json!("maybe"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("boolean, integer"), "{}", error);
+}
+
+#[test]
+fn test_fix_and_validate_json_tuples() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "listener": {
+                "type": "array",
+                "prefixItems": [
+                    {"type": "string"},
+                    {"type": "integer"},
+                    {"type": "boolean"}
+                ]
+            }
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("listener".to_string(), json!("web,8080,true"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["listener"], json!(["web", 8080, true]));
+
+    let mut config = Map::new();
+    config.insert("listener".to_string(), json!("web,8080"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Expected 3 items"), "{}", error);
+}
+
+#[test]
+fn test_fix_and_validate_json_follows_refs() {
+    let schema = json!({
+        "type": "object",
+        "definitions": {
+            "port": {"type": "integer"},
+            "server": {
+                "type": "object",
+                "properties": {"port": {"$ref": "#/definitions/port"}}
+            }
+        },
+        "properties": {
+            "port": {"$ref": "#/definitions/port"},
+            "server": {"$ref": "#/definitions/server"}
+        }
+    });
+    let mut config = Map::new();
+    config.insert("port".to_string(), json!("8080"));
+    config.insert("server".to_string(), json!({"port": "9090"}));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["port"], json!(8080));
+    assert_eq!(result["server"]["port"], json!(9090));
+}
+
+#[test]
+fn test_fix_and_validate_json_structured_errors() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "port": {"type": "integer"},
+            "name": {"type": "string", "minLength": 3}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("port".to_string(), json!("eighty"));
+    let error = fix_and_validate_json(&schema, config).unwrap_err();
+    assert_eq!(
+        error,
+        FixError::CoercionFailed {
+            path: "port".to_string(),
+            value: "eighty".to_string(),
+            target: "integer".to_string(),
+            reason: "Unsupported type: Integer".to_string(),
+        }
+    );
+
+    let mut config = Map::new();
+    config.insert("port".to_string(), json!({"nested": true}));
+    let error = fix_and_validate_json(&schema, config).unwrap_err();
+    assert!(matches!(error, FixError::UnsupportedType { ref kind, .. } if kind == "object"));
+
+    let mut config = Map::new();
+    config.insert("name".to_string(), json!("ab"));
+    let error = fix_and_validate_json(&schema, config).unwrap_err();
+    assert!(matches!(&error, FixError::ValidationFailed(messages) if messages.len() == 1));
+    assert_eq!(error.to_string(), "\"ab\" is shorter than 3 characters");
+
+    let error = fix_and_validate_json(&json!({"type": 12}), Map::new()).unwrap_err();
+    assert!(matches!(error, FixError::SchemaCompile(_)));
+}
+
+#[test]
+fn test_v1_and_v2_paths_are_consistent() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "host_name": {"type": "string"},
+                    "maxConns": {"type": "integer"}
+                }
+            }
+        }
+    });
+    let vars = vec![
+        ("COMPAT_DATABASE_PORT".to_string(), "5432".to_string()),
+        ("COMPAT_DATABASE_HOST__NAME".to_string(), "db".to_string()),
+        ("COMPAT_DATABASE_MAXCONNS".to_string(), "10".to_string()),
+        ("COMPAT_EXTRA_KEY".to_string(), "x".to_string()),
+    ];
+
+    let old = v1::process_env_vars_from("COMPAT_", vars.clone()).unwrap();
+    let new = v2::process_env_vars_from("COMPAT_", &schema, vars).unwrap();
+    assert_eq!(old.len(), new.len());
+
+    // Paths agree wherever the schema spells keys the way v1 derives them
+    for env in [
+        "COMPAT_DATABASE_PORT",
+        "COMPAT_DATABASE_HOST__NAME",
+        "COMPAT_EXTRA_KEY",
+    ] {
+        assert_eq!(old[env].path, new[env].path);
+        assert_eq!(old[env].value, new[env].value);
+    }
+    assert_eq!(old["COMPAT_DATABASE_MAXCONNS"].path, "database.maxconns");
+    assert_eq!(new["COMPAT_DATABASE_MAXCONNS"].path, "database.maxConns");
+}
+
+#[test]
+fn test_create_nested_json_collisions() {
+    let mut config = Map::new();
+    create_nested_json(&mut config, "foo.bar", "x").unwrap();
+    let error = create_nested_json(&mut config, "foo.bar.baz", "y").unwrap_err();
+    assert_eq!(
+        error,
+        "Conflicting paths: 'foo.bar' holds a value of type string but 'foo.bar.baz' needs it to be an object"
+    );
+
+    let mut config = Map::new();
+    create_nested_json(&mut config, "foo.bar.baz", "y").unwrap();
+    let error = create_nested_json(&mut config, "foo.bar", "x").unwrap_err();
+    assert!(error.contains("type object"), "{}", error);
+
+    let mut config = Map::new();
+    create_nested_json(&mut config, "hosts.0", "a").unwrap();
+    let error = create_nested_json(&mut config, "hosts.name", "b").unwrap_err();
+    assert!(error.contains("type array"), "{}", error);
+}
+
+#[test]
+fn test_create_nested_json_rejects_huge_array_indices() {
+    for index in ["18446744073709551615", "4000000000"] {
+        let mut config = Map::new();
+        let error = create_nested_json(&mut config, &format!("a.{}", index), "x").unwrap_err();
+        assert!(error.contains(index), "{}", error);
+        assert!(error.contains("exceeds the maximum"), "{}", error);
+    }
+
+    let mut config = Map::new();
+    create_nested_json(&mut config, "a.9999", "x").unwrap();
+    assert_eq!(config["a"].as_array().unwrap().len(), 10_000);
+
+    let schema = json!({"type": "object"});
+    let vars = vec![("HUGE_A_4000000000".to_string(), "x".to_string())];
+    let error = build_config_from(&schema, "HUGE_", vars).unwrap_err();
+    assert!(matches!(error, FixError::InvalidEnv(_)), "{:?}", error);
+}
+
+#[test]
+fn test_create_nested_json_merges_inline_and_indexed_arrays() {
+    let mut config = Map::new();
+    create_nested_json(&mut config, "foo", "a,b").unwrap();
+    create_nested_json(&mut config, "foo.2", "c").unwrap();
+    assert_eq!(Value::Object(config), json!({"foo": ["a", "b", "c"]}));
+
+    // Indexed paths win over the inline list, whichever comes first
+    let mut config = Map::new();
+    create_nested_json(&mut config, "foo.0", "x").unwrap();
+    create_nested_json(&mut config, "foo", "a b c").unwrap();
+    assert_eq!(Value::Object(config), json!({"foo": ["x", "b", "c"]}));
+
+    let schema = json!({
+        "type": "object",
+        "properties": {"foo": {"type": "array", "items": {"type": "string"}}}
+    });
+    let vars = vec![
+        ("MIXED_FOO".to_string(), "a,b".to_string()),
+        ("MIXED_FOO_2".to_string(), "c".to_string()),
+    ];
+    let config = build_config_from(&schema, "MIXED_", vars).unwrap();
+    assert_eq!(Value::Object(config), json!({"foo": ["a", "b", "c"]}));
+}
+
+#[test]
+fn test_create_nested_json_for_schema_splits_by_schema() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "paths": {"type": "array", "items": {"type": "string"}, "x-delimiter": ";"},
+            "slots": {"type": "array", "x-keep-empty": true}
+        }
+    });
+    let options = FixOptions::default();
+
+    let mut config = Map::new();
+    create_nested_json_for_schema(&mut config, &schema, "paths", "/a b;/c d", &options).unwrap();
+    create_nested_json_for_schema(&mut config, &schema, "paths.2", "/e", &options).unwrap();
+    assert_eq!(config["paths"], json!(["/a b", "/c d", "/e"]));
+
+    let mut config = Map::new();
+    create_nested_json_for_schema(&mut config, &schema, "slots.3", "d", &options).unwrap();
+    create_nested_json_for_schema(&mut config, &schema, "slots", "a,,c", &options).unwrap();
+    assert_eq!(config["slots"], json!(["a", "", "c", "d"]));
+
+    // JSON literals keep their items and types
+    let mut config = Map::new();
+    create_nested_json_for_schema(&mut config, &schema, "slots", "[1, \"x y\"]", &options).unwrap();
+    create_nested_json_for_schema(&mut config, &schema, "slots.2", "z", &options).unwrap();
+    assert_eq!(config["slots"], json!([1, "x y", "z"]));
+}
+
+#[test]
+fn test_parse_dotenv() {
+    let content = r#"
+# database settings
+DOTENV_DATABASE_HOST=localhost
+export DOTENV_DATABASE_PORT = 5432
+
+DOTENV_DATABASE_NAME="my app # not a comment"
+DOTENV_DATABASE_USER='admin' # the user
+DOTENV_DATABASE_PASSWORD=secret # inline comment
+"#;
+    let vars = parse_dotenv(content).unwrap();
+    assert_eq!(
+        vars,
+        vec![
+            ("DOTENV_DATABASE_HOST".to_string(), "localhost".to_string()),
+            ("DOTENV_DATABASE_PORT".to_string(), "5432".to_string()),
+            (
+                "DOTENV_DATABASE_NAME".to_string(),
+                "my app # not a comment".to_string()
+            ),
+            ("DOTENV_DATABASE_USER".to_string(), "admin".to_string()),
+            ("DOTENV_DATABASE_PASSWORD".to_string(), "secret".to_string()),
+        ]
+    );
+
+    assert!(parse_dotenv("NO_EQUALS_SIGN").is_err());
+    assert!(parse_dotenv("KEY=\"unterminated").is_err());
+
+    // Escaped quotes don't end the value, and the quotes inside are kept
+    let vars = parse_dotenv(
+        r#"P_Z="a\"b" # comment
+P_X="'quoted'"
+P_W='C:\dir'"#,
+    )
+    .unwrap();
+    assert_eq!(vars[0].1, "a\"b");
+    assert_eq!(vars[1].1, "'quoted'");
+    assert_eq!(vars[2].1, "C:\\dir");
+    let error = parse_dotenv("KEY=\"a\"b\"c\"").unwrap_err();
+    assert!(
+        error.contains("after the closing quote on line 1"),
+        "{}",
+        error
+    );
+
+    // Quoting for the env keeps dotenv values as parsed
+    let env: Vec<(String, String)> = vars
+        .into_iter()
+        .map(|(key, value)| (key, quote_env_value(&value)))
+        .collect();
+    let properties = process_env_vars_from("P_", env).unwrap();
+    assert_eq!(properties["P_Z"].value, "a\"b");
+    assert_eq!(properties["P_X"].value, "'quoted'");
+    assert_eq!(properties["P_W"].value, "C:\\dir");
+}
+
+#[test]
+fn test_fix_and_validate_json_array_split_modes() {
+    let schema = json!({
+        "type": "object",
+        "properties": {"rows": {"type": "array", "items": {"type": "string"}}}
+    });
+    let split = |mode: &str| {
+        let mut config = Map::new();
+        config.insert("rows".to_string(), json!("a,b c,d"));
+        let options = FixOptions {
+            array_split: mode.parse::<ArraySplit>().unwrap(),
+            ..FixOptions::default()
+        };
+        fix_and_validate_json_with_options(&schema, config, &options).unwrap()["rows"].clone()
+    };
+
+    assert_eq!(split("space"), json!(["a,b", "c,d"]));
+    assert_eq!(split("comma"), json!(["a", "b c", "d"]));
+    assert_eq!(split("both"), json!(["a", "b", "c", "d"]));
+    assert_eq!(split("none"), json!(["a,b c,d"]));
+    assert_eq!(split(" "), json!(["a,b", "c,d"]));
+    assert_eq!(split("b"), json!(["a,", "c,d"]));
+    assert!("tab".parse::<ArraySplit>().is_err());
+}
+
+#[test]
+fn test_create_nested_json_with_sep() {
+    let mut config = Map::new();
+    create_nested_json_with_sep(&mut config, "versions/v1.2/enabled", "true", '/').unwrap();
+    create_nested_json_with_sep(&mut config, "versions/v1.2/hosts/0", "a.example", '/').unwrap();
+    create_nested_json_with_sep(&mut config, "versions/v1.2/hosts/1", "b.example", '/').unwrap();
+
+    assert_eq!(
+        Value::Object(config),
+        json!({
+            "versions": {
+                "v1.2": {
+                    "enabled": "true",
+                    "hosts": ["a.example", "b.example"]
+                }
+            }
+        })
+    );
+}
+
+#[test]
+fn test_fix_and_validate_json_grouped_integers() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "count": {"type": "integer"},
+            "ids": {"type": "array", "items": {"type": "integer"}}
+        }
+    });
+    let coerce = |count: &str, options: &FixOptions| {
+        let mut config = Map::new();
+        config.insert("count".to_string(), json!(count));
+        fix_and_validate_json_with_options(&schema, config, options)
+            .map(|config| config["count"].clone())
+    };
+
+    let options = FixOptions::default();
+    assert_eq!(coerce("1_000", &options).unwrap(), json!(1000));
+    assert_eq!(coerce("1,000", &options).unwrap(), json!(1000));
+    assert_eq!(coerce("-1,234,567", &options).unwrap(), json!(-1234567));
+    assert!(coerce("1,2,3", &options).is_err());
+    assert!(coerce("1__000", &options).is_err());
+
+    // Only underscores group digits when commas are not configured
+    let options = FixOptions {
+        grouping_separators: vec!['_'],
+        ..FixOptions::default()
+    };
+    assert_eq!(coerce("1_000", &options).unwrap(), json!(1000));
+    assert!(coerce("1,000", &options).is_err());
+
+    // A comma list meant for an array is still split into items
+    let mut config = Map::new();
+    config.insert("ids".to_string(), json!("1,2,3"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["ids"], json!([1, 2, 3]));
+}
+
+#[test]
+fn test_fix_and_validate_json_integer_radixes() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "mode": {"type": "integer"},
+            "mask": {"type": "integer"},
+            "flags": {"type": "integer"},
+            "count": {"type": "integer"},
+            "bad": {"type": "integer"}
+        }
+    });
+    let mut config = Map::new();
+    config.insert("mode".to_string(), json!("0o755"));
+    config.insert("mask".to_string(), json!("0xFF00FF"));
+    config.insert("flags".to_string(), json!("0b1010"));
+    config.insert("count".to_string(), json!("42"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["mode"], json!(0o755));
+    assert_eq!(result["mask"], json!(0xFF00FF));
+    assert_eq!(result["flags"], json!(10));
+    assert_eq!(result["count"], json!(42));
+
+    let mut config = Map::new();
+    config.insert("bad".to_string(), json!("0o789"));
+    assert!(fix_and_validate_json(&schema, config).is_err());
+}
+
+#[test]
+fn test_fix_and_validate_json_number_notations() {
+    let schema = json!({
+        "type": "object",
+        "properties": {"value": {"type": "number"}}
+    });
+    let coerce = |value: &str| {
+        let mut config = Map::new();
+        config.insert("value".to_string(), json!(value));
+        fix_and_validate_json(&schema, config).map(|config| config["value"].clone())
+    };
+
+    assert_eq!(coerce("1e6").unwrap().as_f64(), Some(1e6));
+    assert_eq!(coerce(".5").unwrap().as_f64(), Some(0.5));
+    assert_eq!(coerce("1.5E-3").unwrap().as_f64(), Some(1.5e-3));
+    assert_eq!(coerce("42").unwrap(), json!(42));
+
+    let error = coerce("inf").unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "Cannot coerce 'inf' at 'value' to number: Number 'inf' is not finite, which JSON cannot represent"
+    );
+    assert!(coerce("nan").is_err());
+}
+
+#[test]
+fn test_fix_and_validate_json_enum_case() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "color": {"enum": ["Red", "Green", "Blue"]},
+            "mode": {"enum": ["fast", "FAST"]},
+            "level": {"enum": [1, 2, 3]}
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("color".to_string(), json!("red"));
+    config.insert("level".to_string(), json!("2"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["color"], json!("Red"));
+    assert_eq!(result["level"], json!(2));
+
+    let mut config = Map::new();
+    config.insert("color".to_string(), json!("purple"));
+    assert!(fix_and_validate_json(&schema, config).is_err());
+
+    let mut config = Map::new();
+    config.insert("mode".to_string(), json!("Fast"));
+    assert!(fix_and_validate_json(&schema, config).is_err());
+}
+
+#[test]
+fn test_fix_and_validate_json_bounds() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "server": {
+                "type": "object",
+                "properties": {
+                    "workers": {"type": "integer", "minimum": 1, "maximum": 64}
+                }
+            }
+        }
+    });
+    let config = || {
+        let mut server = Map::new();
+        server.insert("workers".to_string(), json!("0"));
+        let mut config = Map::new();
+        config.insert("server".to_string(), Value::Object(server));
+        config
+    };
+
+    let error = fix_and_validate_json(&schema, config())
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Value 0 at 'server.workers' is below the minimum of 1 (allowed range: 1 to 64)"
+    );
+
+    let options = FixOptions {
+        clamp: true,
+        ..FixOptions::default()
+    };
+    let result = fix_and_validate_json_with_options(&schema, config(), &options).unwrap();
+    assert_eq!(result["server"]["workers"], json!(1));
+}
+
+#[test]
+fn test_fix_and_validate_json_key_value_objects() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "labels": {"type": "object"},
+            "limits": {
+                "type": "object",
+                "additionalProperties": {"type": "integer"}
+            }
+        }
+    });
+
+    let mut config = Map::new();
+    config.insert("labels".to_string(), json!("env=prod,team=payments"));
+    config.insert("limits".to_string(), json!("cpu=2,memory=512"));
+    let result = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(result["labels"], json!({"env": "prod", "team": "payments"}));
+    assert_eq!(result["limits"], json!({"cpu": 2, "memory": 512}));
+
+    let mut config = Map::new();
+    config.insert("labels".to_string(), json!("env=prod,team"));
+    let error = fix_and_validate_json(&schema, config)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "Cannot coerce 'env=prod,team' at 'labels' to object: Invalid key=value pair 'team' in 'env=prod,team'"
+    );
+}
+
+#[test]
+fn test_expected_env_vars() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "log_level": {"type": "string"},
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "replica": {"$ref": "#/$defs/replica"}
+                }
+            },
+            "hosts": {"type": "array", "items": {"type": "string"}},
+            "servers": {
+                "type": "array",
+                "items": {"type": "object", "properties": {"host": {"type": "string"}}}
+            },
+            "point": {"type": "array", "prefixItems": [{"type": "number"}, {"type": "number"}]}
+        },
+        "$defs": {
+            "replica": {"type": "object", "properties": {"url": {"type": "string"}}}
+        }
+    });
+
+    let mut names = expected_env_vars(&schema, "APP_");
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "APP_DATABASE_PORT",
+            "APP_DATABASE_REPLICA_URL",
+            "APP_HOSTS",
+            "APP_HOSTS_*",
+            "APP_LOG__LEVEL",
+            "APP_POINT",
+            "APP_POINT_0",
+            "APP_POINT_1",
+            "APP_SERVERS",
+            "APP_SERVERS_*_HOST",
+        ]
+    );
+}
+
+#[test]
+fn test_v2_wildcard_map_keys() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "app": {
+                "type": "object",
+                "properties": {
+                    "ports": {"type": "object", "additionalProperties": {"type": "integer"}}
+                }
+            }
+        }
+    });
+    let vars = vec![
+        ("WILD_APP_PORTS_HTTP".to_string(), "8080".to_string()),
+        ("WILD_APP_PORTS_HTTPS".to_string(), "8443".to_string()),
+        ("WILD_APP_PORTS_ADMIN_HTTP".to_string(), "9000".to_string()),
+    ];
+
+    let properties = v2::process_env_vars_from("WILD_", &schema, vars).unwrap();
+    assert_eq!(properties["WILD_APP_PORTS_HTTP"].path, "app.ports.http");
+    assert_eq!(
+        properties["WILD_APP_PORTS_ADMIN_HTTP"].path,
+        "app.ports.admin_http"
+    );
+
+    let mut config = Map::new();
+    for property in properties.values() {
+        create_nested_json(&mut config, &property.path, &property.value).unwrap();
+    }
+    let config = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(
+        Value::Object(config),
+        json!({"app": {"ports": {"http": 8080, "https": 8443, "admin_http": 9000}}})
+    );
+    assert_eq!(
+        expected_env_vars(&schema, "WILD_"),
+        vec!["WILD_APP_PORTS_*"]
+    );
+}
+
+#[test]
+fn test_get_properties() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "app": {
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string", "x-strict-string": true},
+                    "debug": {"type": "boolean"},
+                    "hosts": {"type": "array", "items": {"type": "string"}},
+                    "ports": {"type": "object", "additionalProperties": {"$ref": "#/$defs/port"}}
+                }
+            }
+        },
+        "$defs": {"port": {"type": "integer"}}
+    });
+
+    let properties = get_properties(&schema, &schema, "");
+    let types: Vec<(&str, &str)> = properties
+        .iter()
+        .map(|p| (p.path.as_str(), p.property_type.as_str()))
+        .collect();
+    let strict: Vec<&str> = properties
+        .iter()
+        .filter(|p| p.strict_string)
+        .map(|p| p.path.as_str())
+        .collect();
+    assert_eq!(strict, vec!["app.name"]);
+    assert_eq!(
+        types,
+        vec![
+            ("app.debug", "boolean"),
+            ("app.hosts", "array[string]"),
+            ("app.name", "string"),
+            ("app.ports.*", "integer"),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_value() {
+    assert_eq!(
+        parse_value("8080", "integer", "app.ports.http").unwrap(),
+        PropertyValue::Integer(8080)
+    );
+    assert_eq!(
+        parse_value("0.5", "number", "ratio").unwrap(),
+        PropertyValue::Number(0.5)
+    );
+    assert_eq!(
+        parse_value("yes", "boolean", "debug").unwrap(),
+        PropertyValue::Boolean(true)
+    );
+    assert_eq!(
+        parse_value("hello", "string", "name").unwrap(),
+        PropertyValue::String("hello".to_string())
+    );
+    assert_eq!(
+        parse_value("a, b", "array[string]", "hosts").unwrap(),
+        PropertyValue::StringArray(vec!["a".to_string(), "b".to_string()])
+    );
+    assert_eq!(
+        parse_value("1,2,3", "array[integer]", "ids").unwrap(),
+        PropertyValue::IntegerArray(vec![1, 2, 3])
+    );
+    assert_eq!(
+        parse_value("1.5 2", "array[number]", "weights").unwrap(),
+        PropertyValue::NumberArray(vec![1.5, 2.0])
+    );
+    assert_eq!(
+        parse_value("true,off", "array[boolean]", "flags").unwrap(),
+        PropertyValue::BooleanArray(vec![true, false])
+    );
+
+    assert_eq!(
+        parse_value("01234", "string", "zip").unwrap(),
+        PropertyValue::String("01234".to_string())
+    );
+    let strict = ParseOptions {
+        strict_strings: true,
+    };
+    assert!(parse_value_with_options("123", "string", "name", &strict).is_err());
+    for word in ["nan", "inf", "infinity", "-Infinity"] {
+        assert_eq!(
+            parse_value_with_options(word, "string", "name", &strict).unwrap(),
+            PropertyValue::String(word.to_string())
+        );
+    }
+    let error = parse_value("abc", "integer", "app.ports.http").unwrap_err();
+    assert!(error.contains("app.ports.http"), "{}", error);
+    assert!(parse_value("1,x", "array[integer]", "ids").is_err());
+    assert!(parse_value("x", "uuid", "id").is_err());
+}
+
+#[test]
+fn test_parse_value_object_array() {
+    let parsed = parse_value(
+        r#"[{"host": "a", "port": 80}, {"host": "b"}]"#,
+        "array[object]",
+        "app.backends",
+    )
+    .unwrap();
+    let PropertyValue::ObjectArray(objects) = parsed else {
+        panic!("expected an object array, got {:?}", parsed);
+    };
+    assert_eq!(objects.len(), 2);
+    assert_eq!(objects[0]["port"], json!(80));
+    assert_eq!(objects[1]["host"], json!("b"));
+
+    let error = parse_value("[{\"host\": ", "array[object]", "app.backends").unwrap_err();
+    assert!(error.contains("app.backends"), "{}", error);
+    let error = parse_value(r#"[{"host": "a"}, 5]"#, "array[object]", "app.backends").unwrap_err();
+    assert!(error.contains("app.backends.1"), "{}", error);
+    assert!(parse_value(r#"{"host": "a"}"#, "array[object]", "app.backends").is_err());
+}
+
+#[test]
+fn test_parse_value_nullable() {
+    assert_eq!(parse_value("", "null", "x").unwrap(), PropertyValue::Null);
+    assert_eq!(
+        parse_value("null", "null", "x").unwrap(),
+        PropertyValue::Null
+    );
+    assert!(parse_value("1", "null", "x").is_err());
+
+    assert_eq!(
+        parse_value("42", "integer?", "limit").unwrap(),
+        PropertyValue::Integer(42)
+    );
+    assert_eq!(
+        parse_value("", "integer?", "limit").unwrap(),
+        PropertyValue::Null
+    );
+    assert!(parse_value(" ", "integer?", "limit").unwrap().is_null());
+    let error = parse_value("abc", "integer?", "limit").unwrap_err();
+    assert!(error.contains("limit"), "{}", error);
+    assert_eq!(
+        parse_value("a,b", "array[string]?", "tags").unwrap(),
+        PropertyValue::StringArray(vec!["a".to_string(), "b".to_string()])
+    );
+
+    // Nullable unions in the schema come out of get_properties as `T?`
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "limit": {"type": ["integer", "null"]},
+            "label": {"type": ["null", "string"]},
+            "ports": {"type": ["array", "null"], "items": {"type": "integer"}}
+        }
+    });
+    let properties = get_properties(&schema, &schema, "");
+    let property_type = |path: &str| {
+        properties
+            .iter()
+            .find(|p| p.path == path)
+            .map(|p| p.property_type.clone())
+            .unwrap()
+    };
+    assert_eq!(property_type("limit"), "integer?");
+    assert_eq!(property_type("label"), "string?");
+    assert_eq!(property_type("ports"), "array[integer]?");
+    assert_eq!(
+        parse_value("", &property_type("limit"), "limit").unwrap(),
+        PropertyValue::Null
+    );
+    assert_eq!(
+        parse_value("5", &property_type("limit"), "limit").unwrap(),
+        PropertyValue::Integer(5)
+    );
+    assert_eq!(
+        parse_value("80,443", &property_type("ports"), "ports").unwrap(),
+        PropertyValue::IntegerArray(vec![80, 443])
+    );
+}
+
+#[test]
+fn test_property_value_accessors() {
+    let string = PropertyValue::String("api".to_string());
+    assert_eq!(string.as_str(), Some("api"));
+    assert_eq!(string.as_i64(), None);
+    assert_eq!(PropertyValue::Integer(8080).as_i64(), Some(8080));
+    assert_eq!(PropertyValue::Integer(2).as_f64(), Some(2.0));
+    assert_eq!(PropertyValue::Number(0.5).as_f64(), Some(0.5));
+    assert_eq!(PropertyValue::Number(0.5).as_i64(), None);
+    assert_eq!(PropertyValue::Boolean(true).as_bool(), Some(true));
+    assert_eq!(PropertyValue::Boolean(true).as_str(), None);
+
+    let strings = PropertyValue::StringArray(vec!["a".to_string()]);
+    assert_eq!(strings.as_str_array(), Some(&["a".to_string()][..]));
+    assert_eq!(strings.as_i64_array(), None);
+    assert_eq!(
+        PropertyValue::IntegerArray(vec![1, 2]).as_i64_array(),
+        Some(&[1, 2][..])
+    );
+    assert_eq!(
+        PropertyValue::NumberArray(vec![1.5]).as_f64_array(),
+        Some(&[1.5][..])
+    );
+    assert_eq!(
+        PropertyValue::BooleanArray(vec![false]).as_bool_array(),
+        Some(&[false][..])
+    );
+    let objects = PropertyValue::ObjectArray(vec![Map::new()]);
+    assert_eq!(objects.as_object_array().map(<[_]>::len), Some(1));
+}
+
+#[test]
+fn test_property_value_into_json() {
+    let mut object = Map::new();
+    object.insert("host".to_string(), json!("a"));
+    let cases = vec![
+        (PropertyValue::String("api".to_string()), json!("api")),
+        (PropertyValue::Integer(8080), json!(8080)),
+        (PropertyValue::Number(0.5), json!(0.5)),
+        (PropertyValue::Boolean(true), json!(true)),
+        (
+            PropertyValue::StringArray(vec!["a".to_string(), "b".to_string()]),
+            json!(["a", "b"]),
+        ),
+        (PropertyValue::IntegerArray(vec![1, 2]), json!([1, 2])),
+        (
+            PropertyValue::NumberArray(vec![1.5, 2.0]),
+            json!([1.5, 2.0]),
+        ),
+        (
+            PropertyValue::BooleanArray(vec![true, false]),
+            json!([true, false]),
+        ),
+        (
+            PropertyValue::ObjectArray(vec![object]),
+            json!([{"host": "a"}]),
+        ),
+        (PropertyValue::Null, Value::Null),
+    ];
+    for (value, expected) in cases {
+        assert_eq!(value.clone().into_json(), expected);
+        assert_eq!(Value::from(value), expected);
+    }
+}
+
+#[test]
+fn test_process_env_vars_unquotes_values() {
+    let vars = vec![
+        ("QUOTE_ESCAPED".to_string(), r"'it\'s fine'".to_string()),
+        ("QUOTE_INTERIOR".to_string(), r#""a"b"c""#.to_string()),
+        ("QUOTE_LIST".to_string(), "'a, b'".to_string()),
+        ("QUOTE_PATH".to_string(), r#""C:\new\\dir""#.to_string()),
+        ("QUOTE_MISMATCHED".to_string(), r#""abc'"#.to_string()),
+    ];
+
+    let properties = process_env_vars_from("QUOTE_", vars).unwrap();
+    assert_eq!(properties["QUOTE_ESCAPED"].value, "it's fine");
+    assert_eq!(properties["QUOTE_INTERIOR"].value, r#""a"b"c""#);
+    assert_eq!(properties["QUOTE_LIST"].value, "a, b");
+    assert_eq!(properties["QUOTE_PATH"].value, r"C:\new\dir");
+    assert_eq!(properties["QUOTE_MISMATCHED"].value, r#""abc'"#);
+}
+
+#[test]
+fn test_process_env_vars_ignore() {
+    let options = EnvOptions {
+        ignore: vec!["IGNORE_INTERNAL_*".to_string(), "IGNORE_DB".to_string()],
+        ..EnvOptions::default()
+    };
+    let vars = vec![
+        ("IGNORE_INTERNAL_TOKEN".to_string(), "secret".to_string()),
+        ("IGNORE_DB".to_string(), "x".to_string()),
+        ("IGNORE_DB_HOST".to_string(), "db".to_string()),
+        ("IGNORE_NAME".to_string(), "api".to_string()),
+    ];
+
+    let result = process_env_vars_from_with_options("IGNORE_", vars, &options).unwrap();
+
+    // IGNORE_DB would otherwise collide with IGNORE_DB_HOST
+    let mut config = Map::new();
+    for props in result.values() {
+        create_nested_json(&mut config, &props.path, &props.value).unwrap();
+    }
+    assert_eq!(
+        Value::Object(config),
+        json!({"db": {"host": "db"}, "name": "api"})
+    );
+}
+
+#[test]
+fn test_process_env_vars_delimiters() {
+    let vars = vec![
+        ("DELIM__MAX__CONNS".to_string(), "10".to_string()),
+        ("DELIM__DATABASE_HOST".to_string(), "db".to_string()),
+        ("DELIM_NAME".to_string(), "skipped".to_string()),
+    ];
+
+    // The `__` after the prefix is not read as an escaped underscore
+    let options = EnvOptions {
+        prefix_delimiter: "__".to_string(),
+        ..EnvOptions::default()
+    };
+    let result = process_env_vars_from_with_options("DELIM", vars.clone(), &options).unwrap();
+    assert_eq!(result.len(), 2);
+    assert_eq!(result["DELIM__MAX__CONNS"].path, "max_conns");
+    assert_eq!(result["DELIM__DATABASE_HOST"].path, "database.host");
+
+    let options = EnvOptions {
+        prefix_delimiter: "__".to_string(),
+        nesting_separator: "__".to_string(),
+        ..EnvOptions::default()
+    };
+    let result = process_env_vars_from_with_options("DELIM", vars, &options).unwrap();
+    assert_eq!(result["DELIM__MAX__CONNS"].path, "max.conns");
+    assert_eq!(result["DELIM__DATABASE_HOST"].path, "database_host");
+}
+
+#[test]
+fn test_process_env_vars_rejects_unusable_nesting_separators() {
+    let vars = vec![("SEP_A_B".to_string(), "1".to_string())];
+    let pattern = Regex::new("^SEP_(?P<rest>.+)$").unwrap();
+    for separator in ["", "_\0"] {
+        let options = EnvOptions {
+            nesting_separator: separator.to_string(),
+            ..EnvOptions::default()
+        };
+        let error = process_env_vars_from_with_options("SEP_", vars.clone(), &options)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("The nesting separator must not"),
+            "{}",
+            error
+        );
+        assert!(process_env_vars_matching_from(&pattern, vars.clone(), &options).is_err());
+    }
+}
+
+#[test]
+fn test_path_to_env_var_with_options_reverses_delimiters() {
+    let options = EnvOptions {
+        prefix_delimiter: "__".to_string(),
+        ..EnvOptions::default()
+    };
+    assert_eq!(
+        path_to_env_var_with_options("APP", "db.max_conns", &options),
+        "APP__DB_MAX__CONNS"
+    );
+
+    let options = EnvOptions {
+        prefix_delimiter: "__".to_string(),
+        nesting_separator: "__".to_string(),
+        ..EnvOptions::default()
+    };
+    let env = path_to_env_var_with_options("APP", "db.max_conns", &options);
+    assert_eq!(env, "APP__DB__MAX_CONNS");
+    let result =
+        process_env_vars_from_with_options("APP", vec![(env.clone(), "1".to_string())], &options)
+            .unwrap();
+    assert_eq!(result[&env].path, "db.max_conns");
+
+    let schema = json!({
+        "type": "object",
+        "properties": {"db": {"type": "object", "properties": {"port": {"type": "integer"}}}}
+    });
+    assert_eq!(
+        expected_env_vars_with_options(&schema, "APP", &options),
+        vec!["APP__DB__PORT"]
+    );
+    let config = json!({"db": {"port": 5432}});
+    assert_eq!(
+        config_to_env_with_options("APP", config.as_object().unwrap(), &options),
+        vec![("APP__DB__PORT".to_string(), "5432".to_string())]
+    );
+    assert!(mapping_markdown_with_options("APP", &schema, &options).contains("`APP__DB__PORT`"));
+}
+
+#[test]
+fn test_process_env_vars_matching() {
+    let vars = vec![
+        ("SVC1_DATABASE_PORT".to_string(), "5432".to_string()),
+        ("SVC2_NAME".to_string(), "api".to_string()),
+        ("OTHER_NAME".to_string(), "x".to_string()),
+    ];
+
+    let pattern = Regex::new(r"^SVC(?P<idx>\d+)_(?P<rest>.+)$").unwrap();
+    let result =
+        process_env_vars_matching_from(&pattern, vars.clone(), &EnvOptions::default()).unwrap();
+    assert_eq!(result.len(), 2);
+    assert_eq!(result["SVC1_DATABASE_PORT"].path, "1.database.port");
+    assert_eq!(result["SVC2_NAME"].path, "2.name");
+
+    // Without a `rest` group, the first group is mapped
+    let pattern = Regex::new(r"^SVC\d+_(.+)$").unwrap();
+    let result =
+        process_env_vars_matching_from(&pattern, vars.clone(), &EnvOptions::default()).unwrap();
+    assert_eq!(result["SVC2_NAME"].path, "name");
+
+    let pattern = Regex::new(r"^SVC").unwrap();
+    assert!(process_env_vars_matching_from(&pattern, vars, &EnvOptions::default()).is_err());
+
+    // Captured segments are transformed like the rest of the name
+    let vars = vec![
+        ("WEB_DB_PORT".to_string(), "5432".to_string()),
+        ("API_DB_PORT".to_string(), "5433".to_string()),
+    ];
+    let pattern = Regex::new(r"^(?P<svc>[A-Z]+)_(?P<rest>.+)$").unwrap();
+    let result =
+        process_env_vars_matching_from(&pattern, vars.clone(), &EnvOptions::default()).unwrap();
+    assert_eq!(result["WEB_DB_PORT"].path, "web.db.port");
+    let options = EnvOptions {
+        key_map: HashMap::from([("API".to_string(), "apiGateway".to_string())]),
+        ..EnvOptions::default()
+    };
+    let result = process_env_vars_matching_from(&pattern, vars, &options).unwrap();
+    assert_eq!(result["API_DB_PORT"].path, "apiGateway.db.port");
+}
+
+#[test]
+fn test_v2_match_snake_case() {
+    let options = EnvOptions {
+        match_snake_case: true,
+        ..EnvOptions::default()
+    };
+    let resolve = |schema: &Value| {
+        let vars = vec![("SNAKE_POOL_MAX_CONNS".to_string(), "10".to_string())];
+        v2::process_env_vars_from_with_options("SNAKE_", schema, vars, &options)
+            .map(|properties| properties["SNAKE_POOL_MAX_CONNS"].path.clone())
+            .map_err(|e| e.to_string())
+    };
+    let pool = |properties: Value| {
+        json!({
+            "type": "object",
+            "properties": {"pool": {"type": "object", "properties": properties}}
+        })
+    };
+
+    let camel = pool(json!({"maxConns": {"type": "integer"}}));
+    assert_eq!(resolve(&camel).unwrap(), "pool.maxConns");
+
+    let snake = pool(json!({"max_conns": {"type": "integer"}}));
+    assert_eq!(resolve(&snake).unwrap(), "pool.max_conns");
+
+    let both = pool(json!({"maxConns": {"type": "integer"}, "max_conns": {"type": "integer"}}));
+    let error = resolve(&both).unwrap_err();
+    assert!(error.contains("SNAKE_POOL_MAX_CONNS"), "{}", error);
+    assert!(
+        error.contains("'maxConns'") && error.contains("'max_conns'"),
+        "{}",
+        error
+    );
+
+    // Without the option the segments are kept as they are
+    let vars = vec![("SNAKE_POOL_MAX_CONNS".to_string(), "10".to_string())];
+    let properties = v2::process_env_vars_from("SNAKE_", &camel, vars).unwrap();
+    assert_eq!(properties["SNAKE_POOL_MAX_CONNS"].path, "pool.max.conns");
+}
+
+#[test]
+fn test_build_config_from() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "host": {"type": "string"},
+                    "port": {"type": "integer"},
+                    "replicas": {"type": "array", "items": {"type": "string"}}
+                }
+            },
+            "debug": {"type": "boolean", "x-env-presence-bool": true}
+        }
+    });
+    let vars = vec![
+        ("BUILD_DATABASE_HOST".to_string(), "db".to_string()),
+        ("BUILD_DATABASE_PORT".to_string(), "5432".to_string()),
+        ("BUILD_DATABASE_REPLICAS".to_string(), "r1,r2".to_string()),
+        ("BUILD_DEBUG".to_string(), "".to_string()),
+        ("OTHER_NAME".to_string(), "ignored".to_string()),
+    ];
+
+    let config = build_config_from(&schema, "BUILD_", vars).unwrap();
+    assert_eq!(
+        Value::Object(config),
+        json!({
+            "database": {"host": "db", "port": 5432, "replicas": ["r1", "r2"]},
+            "debug": true
+        })
+    );
+
+    let vars = vec![
+        ("BUILD_DATABASE".to_string(), "db".to_string()),
+        ("BUILD_DATABASE_PORT".to_string(), "5432".to_string()),
+    ];
+    let error = build_config_from(&schema, "BUILD_", vars).unwrap_err();
+    assert!(matches!(error, FixError::InvalidEnv(_)));
+}
+
+#[test]
+fn test_create_nested_json_nested_arrays() {
+    let mut config = Map::new();
+    create_nested_json(&mut config, "a.0.b.1.c", "x").unwrap();
+    assert_eq!(
+        Value::Object(config.clone()),
+        json!({"a": [{"b": [null, {"c": "x"}]}]})
+    );
+
+    create_nested_json(&mut config, "a.0.b.0.c", "y").unwrap();
+    assert_eq!(
+        Value::Object(config),
+        json!({"a": [{"b": [{"c": "y"}, {"c": "x"}]}]})
+    );
+
+    let mut config = Map::new();
+    create_nested_json(&mut config, "servers.0.ports.0", "80").unwrap();
+    create_nested_json(&mut config, "matrix.1.0", "m").unwrap();
+    create_nested_json(&mut config, "matrix.0.0", "n").unwrap();
+    assert_eq!(
+        Value::Object(config),
+        json!({"servers": [{"ports": ["80"]}], "matrix": [["n"], ["m"]]})
+    );
+}
+
+#[test]
+fn test_create_nested_json_sparse_indices_are_null() {
+    let mut config = Map::new();
+    create_nested_json(&mut config, "servers.2.host", "c").unwrap();
+    create_nested_json(&mut config, "tags.2", "x").unwrap();
+    assert_eq!(
+        Value::Object(config.clone()),
+        json!({"servers": [null, null, {"host": "c"}], "tags": [null, null, "x"]})
+    );
+
+    let nullable = json!({
+        "type": "object",
+        "properties": {
+            "servers": {
+                "type": "array",
+                "items": {"type": ["object", "null"], "properties": {"host": {"type": "string"}}}
+            },
+            "tags": {"type": "array", "items": {"type": ["string", "null"]}}
+        }
+    });
+    assert!(fix_and_validate_json(&nullable, config.clone()).is_ok());
+
+    let strict = json!({
+        "type": "object",
+        "properties": {"tags": {"type": "array", "items": {"type": "string"}}}
+    });
+    let mut tags = Map::new();
+    create_nested_json(&mut tags, "tags.1", "x").unwrap();
+    let error = fix_and_validate_json(&strict, tags)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("No value was set for 'tags.0'"), "{}", error);
+}
+
+#[test]
+fn test_fix_and_validate_json_runs_several_rounds() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "ports": {"type": "array", "items": {"type": "integer"}}
+        }
+    });
+    let mut config = Map::new();
+    // The literal parses to an array of strings, which a second round coerces
+    config.insert("ports".to_string(), json!(r#"["80", "443"]"#));
+
+    let fixed = fix_and_validate_json(&schema, config.clone()).unwrap();
+    assert_eq!(Value::Object(fixed), json!({"ports": [80, 443]}));
+
+    let options = FixOptions {
+        max_attempts: 1,
+        ..FixOptions::default()
+    };
+    let error = fix_and_validate_json_with_options(&schema, config, &options)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("'ports.0' is not of type integer"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_pattern_properties() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "name": {"type": "string"}
+        },
+        "patternProperties": {
+            "^service_": {"type": "integer"}
+        }
+    });
+
+    // The type of a key missing from `properties` comes from the pattern
+    let mut config = Map::new();
+    create_nested_json(&mut config, "service_auth", "8080").unwrap();
+    let fixed = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(Value::Object(fixed), json!({"service_auth": 8080}));
+
+    let vars = vec![
+        ("PATTERN_SERVICE_AUTH_PORT".to_string(), "9000".to_string()),
+        ("PATTERN_NAME".to_string(), "api".to_string()),
+    ];
+    let properties = v2::process_env_vars_from("PATTERN_", &schema, vars).unwrap();
+    assert_eq!(
+        properties["PATTERN_SERVICE_AUTH_PORT"].path,
+        "service_auth_port"
+    );
+
+    let mut config = Map::new();
+    for property in properties.values() {
+        create_nested_json(&mut config, &property.path, &property.value).unwrap();
+    }
+    let fixed = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(
+        Value::Object(fixed),
+        json!({"name": "api", "service_auth_port": 9000})
+    );
+}
+
+#[test]
+fn test_resolve_ref_unescapes_pointer() {
+    let schema = json!({
+        "definitions": {
+            "foo/bar": {"type": "integer"},
+            "a~b": {"type": "string"},
+            "with space": {"type": "boolean"},
+            "list": [{"type": "null"}],
+            "\u{1}": {"type": "number"}
+        }
+    });
+
+    assert_eq!(
+        resolve_ref(&schema, "#/definitions/foo~1bar"),
+        Some(&json!({"type": "integer"}))
+    );
+    assert_eq!(
+        resolve_ref(&schema, "#/definitions/a~0b"),
+        Some(&json!({"type": "string"}))
+    );
+    assert_eq!(
+        resolve_ref(&schema, "#/definitions/with%20space"),
+        Some(&json!({"type": "boolean"}))
+    );
+    assert_eq!(
+        resolve_ref(&schema, "#/definitions/list/0"),
+        Some(&json!({"type": "null"}))
+    );
+    assert_eq!(resolve_ref(&schema, "#/definitions/foo/bar"), None);
+    assert_eq!(resolve_ref(&schema, "#/definitions/bad%2"), None);
+    // Both characters of an escape must be hex digits, so no sign is allowed
+    assert_eq!(
+        resolve_ref(&schema, "#/definitions/%01"),
+        Some(&json!({"type": "number"}))
+    );
+    assert_eq!(resolve_ref(&schema, "#/definitions/%+1"), None);
+}
+
+#[test]
+fn test_resolve_ref_chain() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "port": {"$ref": "#/definitions/a"}
+        },
+        "definitions": {
+            "a": {"$ref": "#/definitions/b"},
+            "b": {"$ref": "#/definitions/c"},
+            "c": {"type": "integer"},
+            "loop": {"$ref": "#/definitions/loop"},
+            "ping": {"$ref": "#/definitions/pong"},
+            "pong": {"$ref": "#/definitions/ping"}
+        }
+    });
+
+    assert_eq!(
+        resolve_ref(&schema, "#/definitions/a"),
+        Some(&json!({"$ref": "#/definitions/b"}))
+    );
+    assert_eq!(
+        resolve_ref_chain(&schema, "#/definitions/a"),
+        Some(&json!({"type": "integer"}))
+    );
+    assert_eq!(resolve_ref_chain(&schema, "#/definitions/loop"), None);
+    assert_eq!(resolve_ref_chain(&schema, "#/definitions/ping"), None);
+
+    // Coercion sees the type at the end of the chain
+    let mut config = Map::new();
+    create_nested_json(&mut config, "port", "8080").unwrap();
+    let fixed = fix_and_validate_json(&schema, config).unwrap();
+    assert_eq!(Value::Object(fixed), json!({"port": 8080}));
+}
+
+#[test]
+fn test_fix_and_validate_json_forced_draft() {
+    // Draft 7 asserts `format` by default, while later drafts only annotate
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "email": {"type": "string", "format": "email"}
+        }
+    });
+    let mut config = Map::new();
+    config.insert("email".to_string(), json!("not-an-email"));
+
+    assert!(fix_and_validate_json(&schema, config.clone()).is_err());
+
+    let with_draft = |draft| FixOptions {
+        draft: Some(draft),
+        ..FixOptions::default()
+    };
+    assert!(
+        fix_and_validate_json_with_options(&schema, config.clone(), &with_draft(Draft::Draft7))
+            .is_err()
+    );
+    for draft in [Draft::Draft201909, Draft::Draft202012] {
+        let fixed = fix_and_validate_json_with_options(&schema, config.clone(), &with_draft(draft))
+            .unwrap();
+        assert_eq!(Value::Object(fixed), json!({"email": "not-an-email"}));
+    }
+}
+
+#[test]
+fn test_process_env_vars_iter_matches_map() {
+    let vars = vec![
+        ("STREAM_DATABASE_HOST".to_string(), "db".to_string()),
+        ("STREAM_DATABASE_PORT".to_string(), "'5432'".to_string()),
+        ("STREAM_MAX__CONNS".to_string(), "10".to_string()),
+        ("OTHER_NAME".to_string(), "ignored".to_string()),
+    ];
+
+    let map = process_env_vars_from("STREAM_", vars.clone()).unwrap();
+    let mut streamed: Vec<(String, String, String)> =
+        process_env_vars_iter_from("STREAM_", vars, &EnvOptions::default())
+            .map(|p| (p.env, p.path, p.value))
+            .collect();
+    streamed.sort();
+    let collected: Vec<(String, String, String)> = map
+        .into_values()
+        .map(|p| (p.env, p.path, p.value))
+        .collect();
+    assert_eq!(streamed, collected);
+    assert_eq!(streamed.len(), 3);
+
+    // The iterator can be folded into a config without collecting
+    let config = process_env_vars_iter_from(
+        "STREAM_",
+        vec![("STREAM_A_B".to_string(), "x".to_string())],
+        &EnvOptions::default(),
+    )
+    .try_fold(Map::new(), |mut config, p| {
+        create_nested_json(&mut config, &p.path, &p.value).map(|_| config)
+    })
+    .unwrap();
+    assert_eq!(Value::Object(config), json!({"a": {"b": "x"}}));
+}
+
+#[test]
+fn test_resolve_ref() {
+    let schema = json!({
+        "definitions": {
+            "address": {
+                "type": "object",
+                "properties": {
+                    "street": {"type": "string"}
+                }
+            }
+        }
+    });
+
+    let result = resolve_ref(&schema, "#/definitions/address").unwrap();
+    let expected = json!({
+        "type": "object",
+        "properties": {
+            "street": {"type": "string"}
+        }
+    });
+
+    assert_eq!(result, &expected);
+    assert!(resolve_ref(&schema, "#/invalid/path").is_none());
+}
+
+#[test]
+fn test_mapping_markdown() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "required": ["port"],
+                "properties": {
+                    "port": {"type": "integer", "default": 5432},
+                    "max_conns": {"type": "integer"},
+                    "timeout": {"type": ["integer", "null"]},
+                    "separator": {"type": "string", "default": "a|b"}
+                }
+            }
+        }
+    });
+
+    let table = mapping_markdown("APP_", &schema);
+
+    assert!(table.starts_with("| Env Var | Path | Type | Required | Default |"));
+    assert!(table.contains("| `APP_DATABASE_PORT` | `database.port` | integer | yes | `5432` |"));
+    assert!(
+        table.contains("| `APP_DATABASE_MAX__CONNS` | `database.max_conns` | integer | no |  |")
+    );
+    assert!(
+        table.contains(r"| `APP_DATABASE_TIMEOUT` | `database.timeout` | integer\|null | no |  |")
+    );
+    assert!(table.contains(
+        r#"| `APP_DATABASE_SEPARATOR` | `database.separator` | string | no | `"a\|b"` |"#
+    ));
+    // Every row keeps the header's five columns
+    for row in table.lines() {
+        assert_eq!(row.replace(r"\|", "").matches('|').count(), 6, "{}", row);
+    }
+}
+
+#[test]
+fn test_unset_sentinel_removes_defaulted_key() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "host": {"type": "string", "default": "localhost"},
+                    "port": {"type": "integer", "default": 5432}
+                }
+            }
+        }
+    });
+
+    let mut config = Map::new();
+    create_nested_json(&mut config, "database.port", "__unset__").unwrap();
+
+    let unset = take_unset_paths(&mut config, "__unset__");
+    apply_defaults(&schema, &mut config);
+    for path in &unset {
+        remove_path(&mut config, path);
+    }
+
+    assert_eq!(unset, vec!["database.port".to_string()]);
+    assert_eq!(
+        Value::Object(config),
+        json!({"database": {"host": "localhost"}})
+    );
+}
+
+#[test]
+fn test_take_unset_paths_drops_array_elements() {
+    let mut config = Map::new();
+    create_nested_json(&mut config, "tags.0", "a").unwrap();
+    create_nested_json(&mut config, "tags.1", "__unset__").unwrap();
+    create_nested_json(&mut config, "tags.2", "c").unwrap();
+    create_nested_json(&mut config, "servers.0.host", "__unset__").unwrap();
+    create_nested_json(&mut config, "servers.0.port", "80").unwrap();
+
+    let unset = take_unset_paths(&mut config, "__unset__");
+    assert_eq!(unset, vec!["servers.0.host", "tags.1"]);
+    assert_eq!(
+        Value::Object(config),
+        json!({"tags": ["a", "c"], "servers": [{"port": "80"}]})
+    );
 }
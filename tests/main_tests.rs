@@ -1,5 +1,5 @@
 use std::io::Write;
-use std::process::Command;
+use std::process::{Command, Stdio};
 use tempfile::NamedTempFile;
 
 #[test]
@@ -51,3 +51,1970 @@ fn test_main_with_schema_file() {
         assert!(output.status.success());
     }
 }
+
+#[test]
+fn test_main_include_raw() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("RAW_DATABASE_PORT", "5432")
+        .arg("--prefix")
+        .arg("RAW_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--include-raw")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(json["database"]["port"], 5432);
+    assert_eq!(json["_raw"]["database.port"], "5432");
+}
+
+#[test]
+fn test_main_multiple_emit_targets() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "server": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "host": {"type": "string"}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+    let output_file = NamedTempFile::new().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("EMIT_SERVER_PORT", "8080")
+        .env("EMIT_SERVER_HOST", "localhost")
+        .arg("--prefix")
+        .arg("EMIT_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--emit")
+        .arg("json:-")
+        .arg("--emit")
+        .arg(format!("json:{}", output_file.path().display()))
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let from_stdout: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    let written = std::fs::read_to_string(output_file.path()).unwrap();
+    let from_file: serde_json::Value = serde_json::from_str(&written).unwrap();
+
+    assert_eq!(from_stdout["server"]["port"], 8080);
+    assert_eq!(from_stdout, from_file);
+
+    // --output writes JSON to a file while --emit prints YAML
+    let output_file = NamedTempFile::new().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("EMIT_SERVER_PORT", "8080")
+        .env("EMIT_SERVER_HOST", "localhost")
+        .arg("--prefix")
+        .arg("EMIT_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--output")
+        .arg(output_file.path())
+        .arg("--emit")
+        .arg("yaml:-")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let from_stdout: serde_json::Value = serde_yaml::from_slice(&output.stdout).unwrap();
+    let written = std::fs::read_to_string(output_file.path()).unwrap();
+    let from_file: serde_json::Value = serde_json::from_str(&written).unwrap();
+    assert_eq!(from_stdout, from_file);
+    assert_eq!(from_file["server"]["host"], "localhost");
+}
+
+#[test]
+fn test_main_show_overrides() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer", "default": 5432},
+                    "host": {"type": "string", "default": "localhost"}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("OVR_DATABASE_PORT", "6543")
+        .env("OVR_DATABASE_HOST", "localhost")
+        .arg("--prefix")
+        .arg("OVR_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--show-overrides")
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert!(output.status.success());
+    assert!(stderr.contains("database.port: default 5432 -> actual 6543"));
+    assert!(!stderr.contains("database.host"));
+}
+
+#[test]
+fn test_main_schema_and_config_from_stdin() {
+    let schema = r#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "enabled": {"type": "boolean"}
+                }
+            }
+        }
+    }"#;
+    let config = r#"{"database": {"port": "5432", "enabled": "true"}}"#;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .arg("--schema")
+        .arg("-")
+        .arg("--config")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("{}\0{}", schema, config).as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(json["database"]["port"], 5432);
+    assert_eq!(json["database"]["enabled"], true);
+}
+
+#[test]
+fn test_main_emit_failed() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "host": {"type": "string"},
+                    "port": {"type": "integer"}
+                }
+            },
+            "cache": {
+                "type": "object",
+                "properties": {
+                    "ttl": {"type": "integer"}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("FAILED_DATABASE_HOST", "db")
+        .env("FAILED_DATABASE_PORT", "not-a-port")
+        .env("FAILED_CACHE_TTL", "60")
+        .arg("--prefix")
+        .arg("FAILED_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--emit-failed")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert!(!output.status.success());
+    assert_eq!(
+        json,
+        serde_json::json!({"database": {"port": "not-a-port"}})
+    );
+}
+
+#[test]
+fn test_main_warn_suspicious() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "port": {"type": "string"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = |flag: &str| {
+        Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("SUSPICIOUS_PORT", "\"\"8080\"\"")
+            .arg("--prefix")
+            .arg("SUSPICIOUS_")
+            .arg("--schema")
+            .arg(schema_file.path())
+            .arg(flag)
+            .output()
+            .unwrap()
+    };
+
+    let output = run("--warn-suspicious");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success());
+    assert!(stderr.contains("warning: SUSPICIOUS_PORT: value looks like it was quoted twice"));
+
+    let output = run("--strict");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_main_coerces_all_scalar_types() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "number"},
+                    "pool": {"type": "integer"},
+                    "ratio": {"type": "number"},
+                    "enabled": {"type": "boolean"},
+                    "hosts": {"type": "array", "items": {"type": "string"}}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("PARITY_DATABASE_PORT", "5432")
+        .env("PARITY_DATABASE_POOL", "10")
+        .env("PARITY_DATABASE_RATIO", "0.75")
+        .env("PARITY_DATABASE_ENABLED", "false")
+        .env("PARITY_DATABASE_HOSTS", "a.example,b.example")
+        .arg("--prefix")
+        .arg("PARITY_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(json["database"]["port"], 5432);
+    assert_eq!(json["database"]["pool"], 10);
+    assert_eq!(json["database"]["ratio"], 0.75);
+    assert_eq!(json["database"]["enabled"], false);
+    assert_eq!(
+        json["database"]["hosts"],
+        serde_json::json!(["a.example", "b.example"])
+    );
+}
+
+#[test]
+fn test_main_format_env_json() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "host_name": {"type": "string"}
+                }
+            },
+            "debug": {"type": "boolean"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("ENVJSON_DATABASE_PORT", "5432")
+        .env("ENVJSON_DATABASE_HOST__NAME", "db.local")
+        .env("ENVJSON_DEBUG", "yes")
+        .arg("--prefix")
+        .arg("ENVJSON_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--format")
+        .arg("env-json")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!([
+            {"name": "ENVJSON_DATABASE_HOST__NAME", "value": "db.local"},
+            {"name": "ENVJSON_DATABASE_PORT", "value": "5432"},
+            {"name": "ENVJSON_DEBUG", "value": "true"}
+        ])
+    );
+}
+
+#[test]
+fn test_main_set_overrides() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "host": {"type": "string"}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("SETOVR_DATABASE_HOST", "from-env")
+        .arg("--prefix")
+        .arg("SETOVR_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--set")
+        .arg("database.port=5432")
+        .arg("--set")
+        .arg("database.host=from-set")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["database"]["port"], 5432);
+    assert_eq!(json["database"]["host"], "from-set");
+}
+
+#[test]
+fn test_main_env_file() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "port": {"type": "integer"},
+            "host": {"type": "string"},
+            "name": {"type": "string"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let mut env_file = NamedTempFile::new().unwrap();
+    env_file
+        .write_all(
+            b"# comment\nDOTENVCLI_PORT=8080\n\nDOTENVCLI_HOST=\"from-file\"\nDOTENVCLI_NAME=\"'quoted'\"\n",
+        )
+        .unwrap();
+    env_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("DOTENVCLI_HOST", "from-env")
+        .arg("--prefix")
+        .arg("DOTENVCLI_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--env-file")
+        .arg(env_file.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["port"], 8080);
+    assert_eq!(json["host"], "from-env");
+    assert_eq!(json["name"], "'quoted'");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_main_export_env() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "enabled": {"type": "boolean"}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = |command: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("EXPORTENV_DATABASE_PORT", "5432")
+            .env("EXPORTENV_DATABASE_ENABLED", "yes")
+            .arg("--prefix")
+            .arg("EXPORTENV_")
+            .arg("--schema")
+            .arg(schema_file.path())
+            .arg("--export-env")
+            .args(command)
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&[
+        "--",
+        "sh",
+        "-c",
+        "echo \"$EXPORTENV_DATABASE_PORT $EXPORTENV_DATABASE_ENABLED\"",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5432 true\n");
+
+    let output = run(&[]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "export EXPORTENV_DATABASE_ENABLED='true'\nexport EXPORTENV_DATABASE_PORT='5432'\n"
+    );
+}
+
+#[test]
+fn test_main_output_format_yaml() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "ratio": {"type": "number"},
+                    "enabled": {"type": "boolean"},
+                    "hosts": {"type": "array", "items": {"type": "string"}}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("YAMLOUT_DATABASE_PORT", "5432")
+        .env("YAMLOUT_DATABASE_RATIO", "0.5")
+        .env("YAMLOUT_DATABASE_ENABLED", "true")
+        .env("YAMLOUT_DATABASE_HOSTS", "a,b")
+        .arg("--prefix")
+        .arg("YAMLOUT_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--output-format")
+        .arg("yaml")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let yaml: serde_yaml::Value = serde_yaml::from_str(&stdout).unwrap();
+    let database = &yaml["database"];
+    assert_eq!(database["port"].as_i64(), Some(5432));
+    assert_eq!(database["ratio"].as_f64(), Some(0.5));
+    assert_eq!(database["enabled"].as_bool(), Some(true));
+    assert_eq!(
+        database["hosts"],
+        serde_yaml::from_str::<serde_yaml::Value>("[a, b]").unwrap()
+    );
+}
+
+#[test]
+fn test_main_output_format_toml() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "name": {"type": "string"},
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "enabled": {"type": "boolean"},
+                    "replica": {"type": ["string", "null"]}
+                }
+            },
+            "debug": {"type": "boolean"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = |replica: &str| {
+        Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("TOMLOUT_NAME", "app")
+            .env("TOMLOUT_DATABASE_PORT", "5432")
+            .env("TOMLOUT_DATABASE_ENABLED", "true")
+            .env("TOMLOUT_DATABASE_REPLICA", replica)
+            .env("TOMLOUT_DEBUG", "false")
+            .arg("--prefix")
+            .arg("TOMLOUT_")
+            .arg("--schema")
+            .arg(schema_file.path())
+            .arg("--output-format")
+            .arg("toml")
+            .output()
+            .unwrap()
+    };
+
+    let output = run("db2");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let parsed: toml::Value = toml::from_str(&stdout).unwrap();
+    let expected: toml::Value = toml::from_str(
+        r#"
+        name = "app"
+        debug = false
+
+        [database]
+        port = 5432
+        enabled = true
+        replica = "db2"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(parsed, expected);
+
+    let output = run("null");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("null value at 'database.replica'"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_main_output_file() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(br#"{"type": "object", "properties": {"port": {"type": "integer"}}}"#)
+        .unwrap();
+    schema_file.flush().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    std::fs::write(&path, "stale contents that should be truncated").unwrap();
+
+    let run = |output: &std::path::Path| {
+        Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("OUTFILE_PORT", "8080")
+            .arg("--prefix")
+            .arg("OUTFILE_")
+            .arg("--schema")
+            .arg(schema_file.path())
+            .arg("-o")
+            .arg(output)
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&path);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "{\n  \"port\": 8080\n}\n"
+    );
+
+    let output = run(&dir.path().join("missing").join("config.json"));
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_main_check() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(br#"{"type": "object", "properties": {"port": {"type": "integer"}}}"#)
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = |port: &str| {
+        Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("CHECKMODE_PORT", port)
+            .arg("--prefix")
+            .arg("CHECKMODE_")
+            .arg("--schema")
+            .arg(schema_file.path())
+            .arg("--check")
+            .output()
+            .unwrap()
+    };
+
+    let output = run("8080");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "OK\n");
+
+    let output = run("eighty");
+    assert_ne!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Unsupported type: Integer"), "{}", stderr);
+    assert!(!stderr.contains("OK"), "{}", stderr);
+}
+
+#[test]
+fn test_main_logs_stay_off_stdout() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "ports": {"type": "array", "items": {"type": "integer"}}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("LOGGING_PORTS", "80,443")
+        .env("RUST_LOG", "debug")
+        .arg("--prefix")
+        .arg("LOGGING_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["ports"], serde_json::json!([80, 443]));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Coerced '80,443' at 'ports'"), "{}", stderr);
+}
+
+#[test]
+fn test_main_debug_output_on_stderr() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {"port": {"type": "integer"}}
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("DEBUGOUT_DATABASE_PORT", "5432")
+        .arg("--prefix")
+        .arg("DEBUGOUT_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--debug")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["database"]["port"], 5432);
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("DEBUGOUT_DATABASE_PORT -> database.port"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("ENV JSON:"), "{}", stderr);
+    assert!(stderr.contains(r#""port": "5432""#), "{}", stderr);
+
+    // The dump comes before a missing required field fails the run
+    let mut required_schema_file = NamedTempFile::new().unwrap();
+    required_schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {"port": {"type": "integer"}},
+        "required": ["port"]
+    }"#,
+        )
+        .unwrap();
+    required_schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("DEBUGREQ_PROT", "5432")
+        .arg("--prefix")
+        .arg("DEBUGREQ_")
+        .arg("--schema")
+        .arg(required_schema_file.path())
+        .arg("--debug")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("DEBUGREQ_PROT -> prot"), "{}", stderr);
+    assert!(stderr.contains("ENV JSON:"), "{}", stderr);
+    assert!(
+        stderr.contains("missing required field 'port'"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_main_output_is_reproducible() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "hosts": {"type": "array", "items": {"type": "string"}},
+            "zone": {"type": "string"},
+            "alpha": {"type": "object"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = || {
+        Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("REPRO_HOSTS_1", "b")
+            .env("REPRO_HOSTS_0", "a")
+            .env("REPRO_ZONE", "eu")
+            .env("REPRO_ALPHA_Z", "1")
+            .env("REPRO_ALPHA_A", "2")
+            .arg("--prefix")
+            .arg("REPRO_")
+            .arg("--schema")
+            .arg(schema_file.path())
+            .output()
+            .unwrap()
+    };
+
+    let first = run();
+    assert!(first.status.success());
+    for _ in 0..5 {
+        assert_eq!(run().stdout, first.stdout);
+    }
+}
+
+#[test]
+fn test_main_strict_rejects_unmapped_vars() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br##"{
+        "type": "object",
+        "properties": {
+            "database": {"$ref": "#/$defs/database"}
+        },
+        "$defs": {
+            "database": {
+                "type": "object",
+                "properties": {"port": {"type": "integer"}}
+            }
+        }
+    }"##,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = |bogus: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"));
+        command
+            .env("UNMAPPED_DATABASE_PORT", "5432")
+            .arg("--prefix")
+            .arg("UNMAPPED_")
+            .arg("--schema")
+            .arg(schema_file.path())
+            .arg("--strict");
+        if bogus {
+            command.env("UNMAPPED_DATABSE_PORT", "5433");
+        }
+        command.output().unwrap()
+    };
+
+    assert!(run(false).status.success());
+
+    let output = run(true);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success());
+    assert!(stderr.contains("UNMAPPED_DATABSE_PORT maps to 'databse.port'"));
+    assert!(!stderr.contains("UNMAPPED_DATABASE_PORT"));
+}
+
+#[test]
+fn test_main_preserve_case() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "pool": {
+                "type": "object",
+                "properties": {"maxConns": {"type": "integer"}}
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("CASED_pool_maxConns", "10")
+        .arg("--prefix")
+        .arg("CASED_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--preserve-case")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(config, serde_json::json!({"pool": {"maxConns": 10}}));
+}
+
+#[test]
+fn test_main_apply_defaults() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br##"{
+        "type": "object",
+        "properties": {
+            "log_level": {"type": "string", "default": "info"},
+            "database": {"$ref": "#/$defs/database"}
+        },
+        "$defs": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "host": {"type": "string", "default": "localhost"},
+                    "port": {"type": "integer", "default": 5432}
+                }
+            }
+        }
+    }"##,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = |apply: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"));
+        command
+            .env("DEFAULTS_DATABASE_PORT", "6543")
+            .arg("--prefix")
+            .arg("DEFAULTS_")
+            .arg("--schema")
+            .arg(schema_file.path());
+        if apply {
+            command.arg("--apply-defaults");
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    assert_eq!(run(false), serde_json::json!({"database": {"port": 6543}}));
+    assert_eq!(
+        run(true),
+        serde_json::json!({
+            "log_level": "info",
+            "database": {"host": "localhost", "port": 6543}
+        })
+    );
+}
+
+#[test]
+fn test_main_reports_missing_required_fields() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "required": ["database", "name"],
+        "properties": {
+            "name": {"type": "string"},
+            "database": {
+                "type": "object",
+                "required": ["host", "port"],
+                "properties": {
+                    "host": {"type": "string"},
+                    "port": {"type": "integer"}
+                }
+            }
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("REQUIRED_NAME", "api")
+        .env("REQUIRED_DATABASE_HOST", "db")
+        .arg("--prefix")
+        .arg("REQUIRED_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("missing required field 'database.port' (set REQUIRED_DATABASE_PORT)"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("'name'"));
+}
+
+#[test]
+fn test_main_merges_multiple_prefixes() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "host": {"type": "string"},
+                    "port": {"type": "integer"}
+                }
+            },
+            "name": {"type": "string"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = |first: &str, second: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("MULTIA_DATABASE_HOST", "db")
+            .env("MULTIA_DATABASE_PORT", "5432")
+            .env("MULTIB_DATABASE_PORT", "6543")
+            .env("MULTIB_NAME", "api")
+            .arg("--prefix")
+            .arg(first)
+            .arg("--prefix")
+            .arg(second)
+            .arg("--schema")
+            .arg(schema_file.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    assert_eq!(
+        run("MULTIA_", "MULTIB_"),
+        serde_json::json!({"database": {"host": "db", "port": 6543}, "name": "api"})
+    );
+    assert_eq!(
+        run("MULTIB_", "MULTIA_"),
+        serde_json::json!({"database": {"host": "db", "port": 5432}, "name": "api"})
+    );
+}
+
+#[test]
+fn test_main_merges_over_base_config() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "host": {"type": "string"},
+                    "port": {"type": "integer"},
+                    "replicas": {"type": "array", "items": {"type": "string"}}
+                }
+            },
+            "name": {"type": "string"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let mut base_file = NamedTempFile::new().unwrap();
+    base_file
+        .write_all(
+            br#"{
+        "database": {"host": "db", "port": 5432, "replicas": ["r1", "r2"]},
+        "name": "api"
+    }"#,
+        )
+        .unwrap();
+    base_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("BASED_DATABASE_PORT", "6543")
+        .env("BASED_DATABASE_REPLICAS", "r3")
+        .arg("--prefix")
+        .arg("BASED_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--base")
+        .arg(base_file.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        config,
+        serde_json::json!({
+            "database": {"host": "db", "port": 6543, "replicas": ["r3"]},
+            "name": "api"
+        })
+    );
+}
+
+#[test]
+fn test_main_no_coerce_reports_all_mismatches() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "name": {"type": "string"},
+            "database": {
+                "type": "object",
+                "properties": {
+                    "port": {"type": "integer"},
+                    "ssl": {"type": "boolean"}
+                }
+            },
+            "ratio": {"type": ["number", "null"]}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let run = |no_coerce: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"));
+        command
+            .env("NOCOERCE_NAME", "api")
+            .env("NOCOERCE_DATABASE_PORT", "5432")
+            .env("NOCOERCE_DATABASE_SSL", "true")
+            .env("NOCOERCE_RATIO", "0.5")
+            .arg("--prefix")
+            .arg("NOCOERCE_")
+            .arg("--schema")
+            .arg(schema_file.path());
+        if no_coerce {
+            command.arg("--no-coerce");
+        }
+        command.output().unwrap()
+    };
+
+    assert!(run(false).status.success());
+
+    let output = run(true);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!output.status.success());
+    assert!(stderr.contains(r#"Value "5432" at 'database.port' is not of type integer"#));
+    assert!(stderr.contains(r#"Value "true" at 'database.ssl' is not of type boolean"#));
+    assert!(stderr.contains(r#"Value "0.5" at 'ratio' is not of type null or number"#));
+    assert!(!stderr.contains("'name'"));
+}
+
+#[test]
+fn test_main_resolves_external_refs() {
+    let root = tempfile::tempdir().unwrap();
+    let dir = root.path().join("schemas");
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(
+        dir.join("schema.json"),
+        r#"{
+        "type": "object",
+        "properties": {
+            "port": {"$ref": "common.json#/definitions/port"},
+            "hosts": {"$ref": "common.json#/definitions/hosts"}
+        }
+    }"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("common.json"),
+        r##"{
+        "definitions": {
+            "port": {"type": "integer", "minimum": 1},
+            "hosts": {"type": "array", "items": {"$ref": "#/definitions/host"}},
+            "host": {"type": "string"}
+        }
+    }"##,
+    )
+    .unwrap();
+
+    let run = |schema: &str| {
+        Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("EXTREF_PORT", "8080")
+            .env("EXTREF_HOSTS", "a,b")
+            .arg("--prefix")
+            .arg("EXTREF_")
+            .arg("--schema")
+            .arg(dir.join(schema))
+            .arg("--schema-dir")
+            .arg(&dir)
+            .output()
+            .unwrap()
+    };
+
+    let output = run("schema.json");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        config,
+        serde_json::json!({"port": 8080, "hosts": ["a", "b"]})
+    );
+
+    // Refs in the root schema resolve against --schema-dir, wherever the
+    // schema file itself is
+    std::fs::write(
+        root.path().join("root.json"),
+        r#"{"type": "object", "properties": {"port": {"$ref": "common.json#/definitions/port"}}}"#,
+    )
+    .unwrap();
+    let output = run("../root.json");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(config["port"], 8080);
+
+    // Refs may not reach outside the schema directory
+    std::fs::write(root.path().join("outside.json"), r#"{"type": "integer"}"#).unwrap();
+    std::fs::write(
+        dir.join("escape.json"),
+        r#"{"type": "object", "properties": {"port": {"$ref": "../outside.json"}}}"#,
+    )
+    .unwrap();
+    let output = run("escape.json");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("outside the schema directory"));
+}
+
+#[test]
+fn test_main_diffs_against_existing_config() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "database": {
+                "type": "object",
+                "properties": {
+                    "host": {"type": "string"},
+                    "port": {"type": "integer"}
+                }
+            },
+            "name": {"type": "string"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let mut existing_file = NamedTempFile::new().unwrap();
+    existing_file
+        .write_all(br#"{"database": {"host": "db", "port": 5432}}"#)
+        .unwrap();
+    existing_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("DRIFT_DATABASE_HOST", "db")
+        .env("DRIFT_DATABASE_PORT", "6543")
+        .env("DRIFT_NAME", "api")
+        .arg("--prefix")
+        .arg("DRIFT_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--diff")
+        .arg(existing_file.path())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let diff: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        diff,
+        serde_json::json!({
+            "added": {"name": "api"},
+            "removed": {},
+            "changed": {"database.port": {"old": 5432, "new": 6543}}
+        })
+    );
+
+    // No differences means success
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("DRIFT2_DATABASE_HOST", "db")
+        .env("DRIFT2_DATABASE_PORT", "5432")
+        .arg("--prefix")
+        .arg("DRIFT2_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--diff")
+        .arg(existing_file.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_main_reads_schema_with_bom_and_crlf() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            b"\xEF\xBB\xBF{\r\n  \"type\": \"object\",\r\n  \"properties\": {\r\n    \"port\": {\"type\": \"integer\"}\r\n  }\r\n}\r\n",
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("BOM_PORT", "8080")
+        .arg("--prefix")
+        .arg("BOM_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(config, serde_json::json!({"port": 8080}));
+
+    // Invalid JSON names the schema file
+    let mut broken_file = NamedTempFile::new().unwrap();
+    broken_file.write_all(b"{\"type\": ").unwrap();
+    broken_file.flush().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .arg("--schema")
+        .arg(broken_file.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&*broken_file.path().to_string_lossy()),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_main_exit_codes() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(br#"{"type": "object", "properties": {"port": {"type": "integer"}}}"#)
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("EXIT_PORT", "not-a-number")
+        .arg("--prefix")
+        .arg("EXIT_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error: "), "{}", stderr);
+    assert!(stderr.contains("Integer"), "{}", stderr);
+    assert!(stderr.contains("'port'"), "{}", stderr);
+    assert!(stderr.contains("'not-a-number'"), "{}", stderr);
+    assert!(!stderr.contains("Error(\""), "{}", stderr);
+
+    // Schema and IO errors exit with 2
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .arg("--schema")
+        .arg("/nonexistent/schema.json")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+
+    let mut bad_schema_file = NamedTempFile::new().unwrap();
+    bad_schema_file
+        .write_all(br#"{"properties": {"a": {"type": 5}}}"#)
+        .unwrap();
+    bad_schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("EXIT_A", "1")
+        .arg("--prefix")
+        .arg("EXIT_")
+        .arg("--schema")
+        .arg(bad_schema_file.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Failed to compile schema"), "{}", stderr);
+}
+
+#[test]
+fn test_main_lists_unmapped_env_vars() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "host": {"type": "string"},
+            "port": {"type": "integer"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    // DROPA_PORT is overridden by DROPB_PORT on the same path
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("DROPA_HOST", "db")
+        .env("DROPA_PORT", "5432")
+        .env("DROPB_PORT", "6543")
+        .arg("--prefix")
+        .arg("DROPA_")
+        .arg("--prefix")
+        .arg("DROPB_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--list-unmapped")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("unmapped: DROPA_PORT ('port')"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("DROPA_HOST"), "{}", stderr);
+    assert!(!stderr.contains("DROPB_PORT"), "{}", stderr);
+}
+
+#[test]
+fn test_main_without_schema_outputs_raw_config() {
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("RAWCFG_DATABASE_HOST", "db")
+        .env("RAWCFG_DATABASE_PORT", "5432")
+        .env("RAWCFG_HOSTS_0", "a")
+        .arg("--prefix")
+        .arg("RAWCFG_")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        config,
+        serde_json::json!({
+            "database": {"host": "db", "port": "5432"},
+            "hosts": ["a"]
+        })
+    );
+}
+
+/// Serves one HTTP request with `status` and `body` on a local port,
+/// returning the URL to request.
+#[cfg(feature = "remote-schema")]
+fn serve_once(status: &'static str, body: &'static str) -> String {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/schema.json", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        // Read the request up to the blank line ending its headers
+        while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+            line.clear();
+        }
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+        .unwrap();
+    });
+    url
+}
+
+#[cfg(feature = "remote-schema")]
+#[test]
+fn test_main_reads_schema_from_url() {
+    let url = serve_once(
+        "200 OK",
+        r#"{"type": "object", "properties": {"port": {"type": "integer"}}}"#,
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("REMOTE_PORT", "8080")
+        .arg("--prefix")
+        .arg("REMOTE_")
+        .arg("--schema")
+        .arg(&url)
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(config, serde_json::json!({"port": 8080}));
+
+    let url = serve_once("404 Not Found", "");
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .arg("--schema")
+        .arg(&url)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("HTTP 404"), "{}", stderr);
+}
+
+#[test]
+fn test_main_writes_coercion_report() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "port": {"type": "integer"},
+            "debug": {"type": "boolean"},
+            "name": {"type": "string"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let report_path = dir.path().join("report.json");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("AUDIT_PORT", "8080")
+        .env("AUDIT_DEBUG", "yes")
+        .env("AUDIT_NAME", "api")
+        .arg("--prefix")
+        .arg("AUDIT_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--report")
+        .arg(&report_path)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        config,
+        serde_json::json!({"port": 8080, "debug": true, "name": "api"})
+    );
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report_path).unwrap()).unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!([
+            {
+                "path": "debug",
+                "env_var": "AUDIT_DEBUG",
+                "original_string": "yes",
+                "coerced_value": true,
+                "target_type": "boolean"
+            },
+            {
+                "path": "port",
+                "env_var": "AUDIT_PORT",
+                "original_string": "8080",
+                "coerced_value": 8080,
+                "target_type": "integer"
+            }
+        ])
+    );
+}
+
+#[test]
+fn test_main_prefix_regex_captures_into_paths() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "additionalProperties": {
+            "type": "object",
+            "properties": {"port": {"type": "integer"}}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("RGXSVC1_PORT", "8080")
+        .env("RGXSVC2_PORT", "9090")
+        .env("RGXOTHER_PORT", "1")
+        .arg("--prefix-regex")
+        .arg(r"^RGXSVC(?P<idx>\d+)_(?P<rest>.+)$")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        config,
+        serde_json::json!({"1": {"port": 8080}, "2": {"port": 9090}})
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .arg("--prefix-regex")
+        .arg("^RGX")
+        .arg("--prefix")
+        .arg("RGX_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_main_set_replaces_env_arrays() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "paths": {"type": "array", "items": {"type": "string"}, "x-delimiter": ";"}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("SETARR_PATHS", "/a b;/c d")
+        .env("SETARR_PATHS_2", "/e")
+        .arg("--prefix")
+        .arg("SETARR_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["paths"], serde_json::json!(["/a b", "/c d", "/e"]));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("SETARR_PATHS_0", "x")
+        .arg("--prefix")
+        .arg("SETARR_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--set")
+        .arg("paths=y;z")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["paths"], serde_json::json!(["y", "z"]));
+}
+
+#[test]
+fn test_main_env_json_uses_delimiters() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "db": {"type": "object", "properties": {"port": {"type": "integer"}}}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("ENVDELIM__DB_PORT", "5432")
+        .arg("--prefix")
+        .arg("ENVDELIM")
+        .arg("--prefix-delimiter")
+        .arg("__")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--output-format")
+        .arg("env-json")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!([{"name": "ENVDELIM__DB_PORT", "value": "5432"}])
+    );
+}
+
+#[test]
+fn test_main_prefix_regex_rejects_env_output() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(br#"{"type": "object", "properties": {"port": {"type": "integer"}}}"#)
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    for args in [
+        vec!["--format", "env-json"],
+        vec!["--emit", "env-json:-"],
+        vec!["--export-env"],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+            .env("RXOUT1_PORT", "80")
+            .arg("--prefix-regex")
+            .arg(r"^RXOUT(?P<idx>\d+)_(?P<rest>.+)$")
+            .arg("--schema")
+            .arg(schema_file.path())
+            .args(&args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("--prefix-regex"), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_main_pattern_properties() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "patternProperties": {"^service_": {"type": "integer"}}
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("PATPROP_SERVICE_AUTH_PORT", "5")
+        .arg("--prefix")
+        .arg("PATPROP_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json, serde_json::json!({"service_auth_port": 5}));
+}
+
+#[test]
+fn test_main_presence_flags_keep_document_values() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "verbose": {"type": "boolean", "x-env-presence-bool": true},
+            "metrics": {"type": "boolean", "x-env-presence-bool": true}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+    let mut document_file = NamedTempFile::new().unwrap();
+    document_file
+        .write_all(br#"{"verbose": true, "metrics": true}"#)
+        .unwrap();
+    document_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .arg("--prefix")
+        .arg("PRESDOC_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--config")
+        .arg(document_file.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json, serde_json::json!({"verbose": true, "metrics": true}));
+
+    // Flags no env var sets keep the base value; set ones become true
+    let mut base_file = NamedTempFile::new().unwrap();
+    base_file
+        .write_all(br#"{"verbose": false, "metrics": true}"#)
+        .unwrap();
+    base_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("PRESBASE_VERBOSE", "")
+        .arg("--prefix")
+        .arg("PRESBASE_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--base")
+        .arg(base_file.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json, serde_json::json!({"verbose": true, "metrics": true}));
+}
+
+#[test]
+fn test_main_matches_build_config_from() {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "maxConns": {"type": "integer"},
+            "svc": {
+                "type": "object",
+                "properties": {"service_auth_port": {"type": "integer"}}
+            }
+        }
+    });
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(schema.to_string().as_bytes())
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let vars = [
+        ("LIBCLI_MAXCONNS", "3"),
+        ("LIBCLI_SVC_SERVICE_AUTH_PORT", "4"),
+    ];
+    let vars_owned = vars.map(|(key, value)| (key.to_string(), value.to_string()));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .envs(vars)
+        .arg("--prefix")
+        .arg("LIBCLI_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let cli: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    let lib =
+        env_to_schema_json::build_config_from(&schema, "LIBCLI_", vars_owned.clone()).unwrap();
+    assert_eq!(cli, serde_json::Value::Object(lib));
+    assert_eq!(
+        cli,
+        serde_json::json!({"maxConns": 3, "svc": {"service": {"auth": {"port": "4"}}}})
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .envs(vars)
+        .arg("--prefix")
+        .arg("LIBCLI_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--match-snake-case")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let cli: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    let options = env_to_schema_json::EnvOptions {
+        match_snake_case: true,
+        ..Default::default()
+    };
+    let lib = env_to_schema_json::build_config_from_with_options(
+        &schema, "LIBCLI_", vars_owned, &options,
+    )
+    .unwrap();
+    assert_eq!(cli, serde_json::Value::Object(lib));
+    assert_eq!(
+        cli,
+        serde_json::json!({"maxConns": 3, "svc": {"service_auth_port": 4}})
+    );
+}
+
+#[test]
+fn test_main_unset_sentinel() {
+    let mut schema_file = NamedTempFile::new().unwrap();
+    schema_file
+        .write_all(
+            br#"{
+        "type": "object",
+        "properties": {
+            "host": {"type": "string", "default": "localhost"},
+            "port": {"type": "integer", "default": 5432},
+            "tags": {"type": "array", "items": {"type": "string"}}
+        }
+    }"#,
+        )
+        .unwrap();
+    schema_file.flush().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_env-to-schema-json"))
+        .env("UNSETCLI_PORT", "__gone__")
+        .env("UNSETCLI_TAGS_0", "a")
+        .env("UNSETCLI_TAGS_1", "__gone__")
+        .env("UNSETCLI_TAGS_2", "c")
+        .arg("--prefix")
+        .arg("UNSETCLI_")
+        .arg("--schema")
+        .arg(schema_file.path())
+        .arg("--apply-defaults")
+        .arg("--unset-sentinel")
+        .arg("__gone__")
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({"host": "localhost", "tags": ["a", "c"]})
+    );
+}